@@ -0,0 +1,62 @@
+// Compares the sendfile fast path against buffered reads over loopback
+// Run with: cargo bench --bench zero_copy [size in MB]
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Instant;
+use netfolder::encoding::{FileReceiver, FileTransmitter};
+
+fn transfer(path: &str, zero_copy: bool) -> f64 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind loopback");
+    let addr = listener.local_addr().unwrap();
+
+    let sink = thread::spawn(move || {
+        let (mut stream, _addr) = listener.accept().expect("Unable to accept");
+        // Received files are renamed into place, so this can't be /dev/null
+        FileReceiver::new().get_file("received.bin", 0, &mut stream).expect("Transfer failed");
+    });
+
+    let mut stream = TcpStream::connect(addr).expect("Unable to connect");
+    let mut transmitter = FileTransmitter::new();
+    transmitter.set_zero_copy(zero_copy);
+
+    let instant = Instant::now();
+    transmitter.host_file(path, &mut stream).expect("Transfer failed");
+    sink.join().unwrap();
+    instant.elapsed().as_secs_f64()
+}
+
+fn main() {
+    // cargo passes --bench, skip anything that isn't a size
+    let size_mb: usize = env::args().skip(1).find_map(|a| a.parse().ok()).unwrap_or(512);
+
+    let dir = env::temp_dir().join(format!("netfolder-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Unable to create bench directory");
+    env::set_current_dir(&dir).expect("Unable to enter bench directory");
+
+    let path = dir.join("payload.bin");
+    let mut file = File::create(&path).expect("Unable to create payload");
+    let block: Vec<u8> = (0..1024 * 1024).map(|i| (i * 7 + 13) as u8).collect();
+    for _ in 0..size_mb {
+        file.write_all(&block).expect("Unable to write payload");
+    }
+    drop(file);
+
+    let path = path.to_str().unwrap();
+    let mut results = Vec::new();
+    for (name, zero_copy) in [("buffered", false), ("sendfile", true)].iter() {
+        // Warm the page cache so both runs read from memory
+        transfer(path, *zero_copy);
+        let seconds = transfer(path, *zero_copy);
+        results.push((name, size_mb as f64 / seconds));
+    }
+
+    println!();
+    for (name, rate) in results.iter() {
+        println!("{:>10}: {:>8.1} MB/s ({} MB)", name, rate, size_mb);
+    }
+
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,71 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use crate::listing;
+
+// Size and contents of a file, empty when it isn't one or can't be read
+#[derive(Debug, Default, Serialize)]
+pub struct FileInfo {
+    pub size: Option<u64>,
+    pub hash: Option<String>
+}
+
+impl FileInfo {
+    pub fn of(path: &Path) -> FileInfo {
+        let size = std::fs::metadata(path).ok().filter(|m| m.is_file()).map(|m| m.len());
+        let hash = size.and_then(|_| listing::hash_file(path).ok())
+            .map(|hash| hash.iter().map(|b| format!("{:02x}", b)).collect());
+        FileInfo { size, hash }
+    }
+}
+
+// One line of the audit log, written once a request has finished either way
+#[derive(Debug, Serialize)]
+pub struct Record<'a> {
+    pub time: String,
+    pub peer: String,
+    pub user: &'a str,
+    pub action: &'a str,
+    pub path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<&'a str>,
+    #[serde(flatten)]
+    pub file: FileInfo,
+    pub duration_ms: f64,
+    pub result: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+// JSON lines appended to a file shared by every connection, does nothing when there's no file
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    file: Option<Arc<Mutex<File>>>
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { file: Some(Arc::new(Mutex::new(file))) })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn write(&self, record: &Record) {
+        let file = match self.file.as_ref() {
+            Some(file) => file,
+            None => return
+        };
+        let mut line = serde_json::to_string(record).expect("Audit records always serialize");
+        line.push('\n');
+        // One write per line so records from different connections never interleave
+        let mut file = file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+            tracing::error!("Unable to write to the audit log: {}", e);
+        }
+    }
+}
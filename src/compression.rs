@@ -0,0 +1,138 @@
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq, Eq)]
+pub enum Codec {
+    None=0x0,
+    Zstd=0x1,
+    Lz4=0x2
+}
+
+// Extensions of formats that are already compressed, not worth another pass
+const PRECOMPRESSED: &[&str] = &[
+    "gz", "tgz", "bz2", "xz", "txz", "zst", "lz4", "zip", "7z", "rar", "jar",
+    "jpg", "jpeg", "png", "gif", "webp", "mp3", "mp4", "mkv", "avi", "mov", "ogg", "flac",
+    "docx", "xlsx", "pptx", "odt"
+];
+
+impl Codec {
+    pub fn from_u8(value: u8) -> Option<Codec> {
+        match value {
+            0x0 => Some(Codec::None),
+            0x1 => Some(Codec::Zstd),
+            0x2 => Some(Codec::Lz4),
+            _ => None
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" | "off" => Some(Codec::None),
+            "zstd" => Some(Codec::Zstd),
+            "lz4" => Some(Codec::Lz4),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4"
+        }
+    }
+
+    // Bitmask of every codec this build understands, exchanged in the hello packet
+    pub fn supported() -> u8 {
+        (1 << Codec::Zstd as u8) | (1 << Codec::Lz4 as u8)
+    }
+
+    // Falls back to no compression if the peer can't decode what we want
+    pub fn negotiate(self, peer_mask: u8) -> Codec {
+        if self == Codec::None || peer_mask & Codec::supported() & (1 << self as u8) == 0 {
+            Codec::None
+        }
+        else {
+            self
+        }
+    }
+}
+
+pub fn is_precompressed(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => PRECOMPRESSED.contains(&extension.to_ascii_lowercase().as_str()),
+        None => false
+    }
+}
+
+// Returns the codec actually used, chunks that don't shrink are sent raw
+pub fn compress(codec: Codec, data: &[u8]) -> (Codec, Vec<u8>) {
+    let compressed = match codec {
+        Codec::None => None,
+        Codec::Zstd => zstd::bulk::compress(data, 3).ok(),
+        Codec::Lz4 => Some(lz4_flex::block::compress(data))
+    };
+
+    match compressed {
+        Some(compressed) if compressed.len() < data.len() => (codec, compressed),
+        _ => (Codec::None, data.to_vec())
+    }
+}
+
+pub fn decompress(codec: Codec, data: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let raw = match codec {
+        Codec::None => data.to_vec(),
+        Codec::Zstd => zstd::bulk::decompress(data, raw_len)?,
+        Codec::Lz4 => lz4_flex::block::decompress(data, raw_len).map_err(|e| invalid(e.to_string()))?
+    };
+
+    if raw.len() == raw_len {
+        Ok(raw)
+    }
+    else {
+        Err(invalid(format!("Chunk decoded to {} bytes, expected {}", raw.len(), raw_len)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let data = b"netfolder ".repeat(1000);
+        for codec in [Codec::None, Codec::Zstd, Codec::Lz4] {
+            let (used, compressed) = compress(codec, &data);
+            assert_eq!(used, codec);
+            assert_eq!(decompress(used, &compressed, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn incompressible_chunks_go_raw() {
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(compress(Codec::Zstd, &data), (Codec::None, data));
+    }
+
+    #[test]
+    fn raw_len_has_to_match() {
+        let data = b"netfolder ".repeat(1000);
+        for codec in [Codec::None, Codec::Zstd, Codec::Lz4] {
+            let (used, compressed) = compress(codec, &data);
+            assert!(decompress(used, &compressed, data.len() - 1).is_err());
+            assert!(decompress(used, &compressed, data.len() + 1).is_err());
+        }
+    }
+
+    #[test]
+    fn negotiation_falls_back_to_none() {
+        assert_eq!(Codec::Zstd.negotiate(Codec::supported()), Codec::Zstd);
+        assert_eq!(Codec::Zstd.negotiate(1 << Codec::Lz4 as u8), Codec::None);
+        assert_eq!(Codec::None.negotiate(Codec::supported()), Codec::None);
+    }
+}
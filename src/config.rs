@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::compression::Codec;
+use crate::throttle;
+
+// Client settings for one server, anything left out falls back to the command line defaults
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    // Where remote paths are taken from
+    pub remote: Option<String>,
+    pub limit: Option<String>,
+    pub compress: Option<String>,
+    pub delta: bool,
+    pub retries: Option<u32>,
+    pub timeout: Option<u64>,
+    // Accepted so configs can be written ahead, the protocol has no logins or TLS to use them
+    pub credentials: Option<String>,
+    pub tls_pin: Option<String>
+}
+
+impl Profile {
+    fn check(&self, name: &str) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("Profile {}: {}", name, message));
+        if self.host.is_none() {
+            return Err(invalid(String::from("missing host")));
+        }
+        if let Some(limit) = self.limit.as_ref().filter(|l| throttle::parse_rate(l).is_none()) {
+            return Err(invalid(format!("invalid limit {}, expected a rate like 10M, or off", limit)));
+        }
+        if let Some(compress) = self.compress.as_ref().filter(|c| Codec::from_name(c).is_none()) {
+            return Err(invalid(format!("invalid compress {}, expected none, zstd or lz4", compress)));
+        }
+        Ok(())
+    }
+
+    // Settings that are read but can't do anything yet
+    pub fn unsupported(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.credentials.is_some() {
+            names.push("credentials");
+        }
+        if self.tls_pin.is_some() {
+            names.push("tls_pin");
+        }
+        names
+    }
+}
+
+// ~/.config/netfolder/config.toml, with a [profiles.<name>] table per server
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub profiles: BTreeMap<String, Profile>
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("netfolder").join("config.toml"))
+}
+
+impl Config {
+    // Without an explicit path a missing config is the same as an empty one
+    pub fn load(path: Option<&Path>) -> io::Result<Config> {
+        let (path, required) = match path {
+            Some(path) => (PathBuf::from(path), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default())
+            }
+        };
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(Config::default()),
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+        };
+        let config: Config = toml::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+
+        for (name, profile) in config.profiles.iter() {
+            profile.check(name)?;
+        }
+        Ok(config)
+    }
+
+    pub fn profile(&self, name: &str) -> io::Result<&Profile> {
+        self.profiles.get(name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No profile named {}", name)))
+    }
+}
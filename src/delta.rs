@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use crate::net;
+
+pub const STRONG_SIZE: usize = 16;
+// Weak checksum, strong hash
+pub const BLOCK_SIG_SIZE: usize = 4 + STRONG_SIZE;
+
+const MIN_BLOCK_SIZE: u32 = 1024;
+const MAX_BLOCK_SIZE: u32 = 64 * 1024;
+// 80 MB of block signatures, enough for files of about 256 GB
+const MAX_BLOCKS: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: [u8; STRONG_SIZE]
+}
+
+// Checksums of every block of the file the receiver already has
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub block_size: u32,
+    pub basis_len: u64,
+    pub blocks: Vec<BlockSignature>
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Op {
+    // Bytes the receiver already has, at this offset of its old file
+    Copy { offset: u64, len: u64 },
+    Literal(Vec<u8>)
+}
+
+// rsync style rolling checksum, can slide one byte at a time
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Rolling {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        let len = data.len() as u32;
+        for (i, x) in data.iter().enumerate() {
+            a = a.wrapping_add(*x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*x as u32));
+        }
+        Rolling { a, b, len }
+    }
+
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    // Window shrinks at the end of the file
+    fn roll_out(&mut self, out: u8) {
+        self.a = self.a.wrapping_sub(out as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32));
+        self.len -= 1;
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+pub fn strong(data: &[u8]) -> [u8; STRONG_SIZE] {
+    let mut strong = [0; STRONG_SIZE];
+    strong.copy_from_slice(&blake3::hash(data).as_bytes()[..STRONG_SIZE]);
+    strong
+}
+
+// Roughly sqrt(len) like rsync, so big files don't send huge signatures
+pub fn block_size_for(len: u64) -> u32 {
+    let size = (len as f64).sqrt() as u32;
+    let size = (size / MIN_BLOCK_SIZE) * MIN_BLOCK_SIZE;
+    size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+impl Signature {
+    pub fn empty() -> Signature {
+        Signature { block_size: MIN_BLOCK_SIZE, basis_len: 0, blocks: Vec::new() }
+    }
+
+    // Missing files get an empty signature, everything is sent as literal data
+    pub fn of_path(path: &Path) -> io::Result<Signature> {
+        match File::open(path) {
+            Ok(file) => {
+                let len = file.metadata()?.len();
+                Signature::compute(file, len)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Signature::empty()),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub fn compute<R: Read>(reader: R, len: u64) -> io::Result<Signature> {
+        let block_size = block_size_for(len);
+        let mut reader = BufReader::new(reader);
+        let mut block = vec![0; block_size as usize];
+        let mut blocks = Vec::new();
+        let mut basis_len = 0;
+
+        loop {
+            let bytes = read_full(&mut reader, &mut block)?;
+            if bytes == 0 {
+                break;
+            }
+
+            let data = &block[..bytes];
+            blocks.push(BlockSignature { weak: Rolling::new(data).digest(), strong: strong(data) });
+            basis_len += bytes as u64;
+        }
+
+        Ok(Signature { block_size, basis_len, blocks })
+    }
+
+    // The block signatures that follow a signature packet, which come from the peer and are checked before anything is read
+    pub fn read<R: Read>(mut reader: R, block_size: u32, basis_len: u64, count: u32) -> io::Result<Signature> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+            return Err(invalid(format!("Invalid delta block size {}", block_size)));
+        }
+        if count as u64 != basis_len.div_ceil(block_size as u64) {
+            return Err(invalid(format!("{} blocks of {} bytes don't make a file of {} bytes", count, block_size, basis_len)));
+        }
+        if count as u64 > MAX_BLOCKS {
+            return Err(invalid(format!("Signature of {} blocks is too large", count)));
+        }
+
+        let mut blocks = vec![0; count as usize * BLOCK_SIG_SIZE];
+        reader.read_exact(&mut blocks)?;
+        Ok(Signature { block_size, basis_len, blocks: Signature::blocks_from_bytes(&blocks) })
+    }
+
+    fn block_len(&self, index: usize) -> usize {
+        let start = index as u64 * self.block_size as u64;
+        self.basis_len.saturating_sub(start).min(self.block_size as u64) as usize
+    }
+
+    // Block signatures as they go on the wire, after the signature packet
+    pub fn blocks_to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.blocks.len() * BLOCK_SIG_SIZE);
+        for block in self.blocks.iter() {
+            bytes.extend_from_slice(&block.weak.to_le_bytes());
+            bytes.extend_from_slice(&block.strong);
+        }
+        bytes
+    }
+
+    pub fn blocks_from_bytes(bytes: &[u8]) -> Vec<BlockSignature> {
+        bytes.chunks_exact(BLOCK_SIG_SIZE).map(|b| {
+            let mut weak = [0; 4];
+            weak.copy_from_slice(&b[..4]);
+            let mut strong = [0; STRONG_SIZE];
+            strong.copy_from_slice(&b[4..]);
+            BlockSignature { weak: u32::from_le_bytes(weak), strong }
+        }).collect()
+    }
+}
+
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(bytes) => filled += bytes,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(filled)
+}
+
+// Walks the new file and emits the ops that rebuild it from the signed file
+pub fn diff<R: Read, F: FnMut(Op) -> io::Result<()>>(signature: &Signature, reader: R, mut emit: F) -> io::Result<()> {
+    let block_size = signature.block_size as usize;
+    let mut table: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in signature.blocks.iter().enumerate() {
+        table.entry(block.weak).or_default().push(i);
+    }
+
+    let mut reader = BufReader::new(reader);
+    let mut read_buf = vec![0; net::CHUNK_SIZE];
+    // buf[..start] is pending literal data, buf[start..] is the window and what follows
+    let mut buf: Vec<u8> = Vec::new();
+    let mut start = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    let mut copy: Option<(u64, u64)> = None;
+
+    loop {
+        while !eof && buf.len() < start + block_size {
+            let bytes = reader.read(&mut read_buf)?;
+            if bytes == 0 {
+                eof = true;
+            }
+            buf.extend_from_slice(&read_buf[..bytes]);
+        }
+
+        let end = (start + block_size).min(buf.len());
+        if start == end {
+            break;
+        }
+
+        let window = &buf[start..end];
+        let sum = *rolling.get_or_insert_with(|| Rolling::new(window));
+        let found = table.get(&sum.digest()).and_then(|candidates| {
+            let hash = strong(window);
+            candidates.iter().copied()
+                .find(|i| signature.block_len(*i) == window.len() && signature.blocks[*i].strong == hash)
+        });
+
+        if let Some(index) = found {
+            if start > 0 {
+                if let Some((offset, len)) = copy.take() {
+                    emit(Op::Copy { offset, len })?;
+                }
+                emit(Op::Literal(buf[..start].to_vec()))?;
+            }
+
+            let offset = index as u64 * block_size as u64;
+            let len = window.len() as u64;
+            copy = match copy {
+                Some((copy_offset, copy_len)) if copy_offset + copy_len == offset => Some((copy_offset, copy_len + len)),
+                Some((copy_offset, copy_len)) => {
+                    emit(Op::Copy { offset: copy_offset, len: copy_len })?;
+                    Some((offset, len))
+                },
+                None => Some((offset, len))
+            };
+
+            buf.drain(..end);
+            start = 0;
+            rolling = None;
+            continue;
+        }
+
+        // No match, the first byte of the window becomes literal data
+        let out = buf[start];
+        start += 1;
+        let mut sum = sum;
+        if start + block_size <= buf.len() {
+            sum.roll(out, buf[start + block_size - 1]);
+            rolling = Some(sum);
+        }
+        else if eof {
+            sum.roll_out(out);
+            rolling = Some(sum);
+        }
+        else {
+            // Refill first, then recompute over the new window
+            rolling = None;
+        }
+
+        if start >= net::CHUNK_SIZE {
+            if let Some((offset, len)) = copy.take() {
+                emit(Op::Copy { offset, len })?;
+            }
+            emit(Op::Literal(buf[..start].to_vec()))?;
+            buf.drain(..start);
+            start = 0;
+        }
+    }
+
+    if let Some((offset, len)) = copy.take() {
+        emit(Op::Copy { offset, len })?;
+    }
+    if !buf.is_empty() {
+        emit(Op::Literal(buf))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic bytes that don't repeat within a block
+    fn bytes(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    // What the receiver does with the ops
+    fn patch(basis: &[u8], new: &[u8]) -> (Vec<u8>, Vec<Op>) {
+        let signature = Signature::compute(basis, basis.len() as u64).unwrap();
+        let mut ops = Vec::new();
+        diff(&signature, new, |op| { ops.push(op); Ok(()) }).unwrap();
+
+        let mut patched = Vec::new();
+        for op in ops.iter() {
+            match op {
+                Op::Copy { offset, len } => patched.extend_from_slice(&basis[*offset as usize..(offset + len) as usize]),
+                Op::Literal(data) => patched.extend_from_slice(data)
+            }
+        }
+        (patched, ops)
+    }
+
+    #[test]
+    fn rolling_matches_a_fresh_checksum() {
+        let data = bytes(4096, 1);
+        let window = 1024;
+        let mut sum = Rolling::new(&data[..window]);
+        for start in 1..=data.len() - window {
+            sum.roll(data[start - 1], data[start + window - 1]);
+            assert_eq!(sum.digest(), Rolling::new(&data[start..start + window]).digest());
+        }
+
+        let tail = data.len() - window;
+        for start in tail + 1..data.len() {
+            sum.roll_out(data[start - 1]);
+            assert_eq!(sum.digest(), Rolling::new(&data[start..]).digest());
+        }
+    }
+
+    #[test]
+    fn unchanged_file_is_one_copy() {
+        let basis = bytes(10000, 2);
+        let (patched, ops) = patch(&basis, &basis);
+        assert_eq!(patched, basis);
+        assert_eq!(ops, [Op::Copy { offset: 0, len: basis.len() as u64 }]);
+    }
+
+    #[test]
+    fn edited_file_round_trips() {
+        let basis = bytes(50000, 3);
+        let mut new = basis[..20000].to_vec();
+        new.extend_from_slice(b"inserted in the middle");
+        new.extend_from_slice(&basis[21000..45000]);
+        new.extend_from_slice(&bytes(3000, 4));
+
+        let (patched, ops) = patch(&basis, &new);
+        assert_eq!(patched, new);
+        let copied: u64 = ops.iter().map(|op| match op { Op::Copy { len, .. } => *len, _ => 0 }).sum();
+        assert!(copied >= 40000, "only {} bytes copied", copied);
+    }
+
+    #[test]
+    fn new_file_is_all_literal() {
+        let new = bytes(5000, 5);
+        let (patched, ops) = patch(&[], &new);
+        assert_eq!(patched, new);
+        assert!(ops.iter().all(|op| matches!(op, Op::Literal(_))));
+    }
+
+    #[test]
+    fn read_checks_the_peer() {
+        let basis = bytes(5000, 6);
+        let signature = Signature::compute(&basis[..], basis.len() as u64).unwrap();
+        let wire = signature.blocks_to_bytes();
+        let count = signature.blocks.len() as u32;
+
+        let read = Signature::read(&wire[..], signature.block_size, signature.basis_len, count).unwrap();
+        assert_eq!(read.blocks, signature.blocks);
+
+        assert!(Signature::read(&wire[..], 0, signature.basis_len, count).is_err());
+        assert!(Signature::read(&wire[..], MAX_BLOCK_SIZE + 1, signature.basis_len, count).is_err());
+        assert!(Signature::read(&wire[..], signature.block_size, signature.basis_len, count + 1).is_err());
+        assert!(Signature::read(&wire[..], 1024, u64::MAX, u32::MAX).is_err());
+        assert!(Signature::read(&wire[..wire.len() - 1], signature.block_size, signature.basis_len, count).is_err());
+    }
+}
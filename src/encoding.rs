@@ -1,124 +1,254 @@
 use std::net::{TcpStream};
-use std::fs::File;
-use std::path::Path;
-use std::io::{Read, Write};
-use crate::net::{self, parse};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use crate::net::{self, parse, create};
+use crate::delta;
+use crate::listing;
+use crate::retry;
 use crate::stats;
+use crate::throttle::{self, Limiter};
+use crate::compression::{self, Codec};
 use indicatif::{ProgressBar, ProgressStyle};
-use std::time::Instant;
+use std::time::Duration;
 
 //Reads from TcpStream, writes to File
 pub struct FileReceiver {
+    limiters: Vec<Limiter>,
+    codec: Codec
+}
 
+impl Default for FileReceiver {
+    fn default() -> FileReceiver {
+        FileReceiver::new()
+    }
 }
 
 impl FileReceiver {
     pub fn new() -> FileReceiver {
-        FileReceiver {}
+        FileReceiver { limiters: Vec::new(), codec: Codec::None }
+    }
+
+    // Compression to ask the sender for
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    pub fn throttle(&mut self, limiter: Limiter) {
+        self.limiters.push(limiter);
     }
 
-    pub fn get_file(&mut self, file_name: &str, _port: u16, stream: &mut TcpStream) -> stats::TransferStats {
+    pub fn get_file(&mut self, file_name: &str, _port: u16, stream: &mut TcpStream) -> io::Result<stats::TransferStats> {
+        note!("Creating file {}", file_name);
+        // Built next to the target and renamed over it once complete
+        let part = part_path(Path::new(file_name));
+        if let Some(parent) = part.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut mtime = 0;
+        match self.receive(file_name, &part, stream, &mut mtime) {
+            Ok(stats) => {
+                std::fs::rename(&part, file_name)?;
+                Ok(stats)
+            },
+            // What arrived before the connection dropped is kept to resume from
+            Err(e) if retry::is_retryable(&e) && mtime != 0 && partial_len(&part) > 0 => Err(e),
+            Err(e) => {
+                std::fs::remove_file(&part).ok();
+                Err(e)
+            }
+        }
+    }
+
+    fn receive(&mut self, file_name: &str, part: &Path, stream: &mut TcpStream, mtime: &mut u64) -> io::Result<stats::TransferStats> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
         let mut buf = [0; net::PACKET_SIZE];
-        println!("Creating file {}", file_name);
-        let mut file = File::create(file_name).expect("File error");
+        // Opened once the first chunk says where the transfer starts
+        let mut file: Option<File> = None;
+        // The old version of the file, for Copy packets of delta transfers
+        let mut basis: Option<File> = None;
 
         let mut stats = stats::TransferStats::new();
-        let mut realtime_stats = stats::RealtimeStats::new();
+        // Started once the first chunk gives the size
+        let mut progress: Option<Progress> = None;
+        let mut resumed_from = 0;
         let mut current_bytes = 0;
-        loop {
-            let available = stream.peek(&mut buf).expect("Unable to peek stream");
-
-            if available == net::PACKET_SIZE {
-                let command = parse::packet(&buf) as u8;
-
-                if command == (net::Code::Data as u8) {
-                    let bytes = stream.read(&mut buf).expect("Unable to read stream");
-                    let (_id, _trans, size) = parse::data(buf);
-                    realtime_stats.set_size(size);
-
-                    if current_bytes + bytes >= size {
-                        let rem = size - current_bytes;
-                        file.write_all(&buf[net::DATA_OFFSET..(net::DATA_OFFSET + rem)]).expect("Failed to write to stream");
-                        realtime_stats.add_bytes(rem);
-                        current_bytes += rem;
-                        break;
+        let mut wire_bytes = 0;
+        let mut wire = Vec::new();
+        let mut complete = false;
+        while !complete {
+            stream.read_exact(&mut buf)?;
+
+            let (bytes, total) = match parse::packet(&buf) {
+                net::Code::Data => {
+                    let chunk = parse::data(&buf);
+                    *mtime = chunk.mtime;
+                    if chunk.wire_len as usize > net::MAX_CHUNK_SIZE || chunk.raw_len as usize > net::MAX_CHUNK_SIZE {
+                        return Err(invalid(format!("Chunk of {} bytes is too large", chunk.wire_len.max(chunk.raw_len))));
                     }
-                    else {
-                        file.write_all(&buf[net::DATA_OFFSET..]).expect("Failed to write to stream");
+                    if file.is_none() {
+                        file = Some(open_part(part, chunk.offset)?);
+                        resumed_from = chunk.offset as usize;
+                        current_bytes = resumed_from;
+                        progress = Some(Progress::new(chunk.total, chunk.offset));
+                    }
+                    let file = file.as_mut().unwrap();
+
+                    wire.resize(chunk.wire_len as usize, 0);
+                    stream.read_exact(&mut wire)?;
+                    throttle::acquire_all(&self.limiters, buf.len() + wire.len());
+                    wire_bytes += buf.len() + wire.len();
+
+                    let bytes = match Codec::from_u8(chunk.codec).ok_or_else(|| invalid(String::from("Unknown compression codec")))? {
+                        Codec::None => {
+                            file.write_all(&wire)?;
+                            wire.len()
+                        },
+                        codec => {
+                            let data = compression::decompress(codec, &wire, chunk.raw_len as usize)?;
+                            file.write_all(&data)?;
+                            data.len()
+                        }
+                    };
+                    // Stamped as it goes with the source's mtime, so a part left behind by a crash
+                    // is only resumed from by the same version of the file
+                    if chunk.mtime != 0 {
+                        file.set_modified(listing::from_nanos(chunk.mtime))?;
+                    }
+                    (bytes, chunk.total)
+                },
+                net::Code::Copy => {
+                    let (basis_offset, len, offset, total, copy_mtime) = parse::copy(&buf);
+                    *mtime = copy_mtime;
+                    throttle::acquire_all(&self.limiters, buf.len());
+                    wire_bytes += buf.len();
+                    if file.is_none() {
+                        file = Some(open_part(part, offset)?);
+                        progress = Some(Progress::new(total, offset));
                     }
+                    let file = file.as_mut().unwrap();
 
-                    current_bytes += bytes - net::DATA_OFFSET;
-                }
-                else {
-                    break;
-                }
-            }
-            // Full packet unavailable, timeout and try again
-            else {
+                    if basis.is_none() {
+                        basis = Some(File::open(file_name)?);
+                    }
+                    let basis = basis.as_mut().unwrap();
+                    basis.seek(SeekFrom::Start(basis_offset))?;
+                    let copied = io::copy(&mut basis.take(len), file)?;
+                    if copied != len {
+                        return Err(invalid(String::from("Delta basis file is shorter than expected")));
+                    }
+                    (len as usize, total)
+                },
+                code => return Err(invalid(format!("Transfer interrupted by {:?} packet", code)))
+            };
+
+            if let Some(progress) = progress.as_mut() {
+                progress.add(bytes);
             }
+            current_bytes += bytes;
+            complete = current_bytes as u64 >= total;
         }
-        stats.stop(current_bytes);
-        stats
+        let peak = progress.map(Progress::finish).unwrap_or(0.0);
+        if let Some(file) = file.as_ref().filter(|_| *mtime != 0) {
+            file.set_modified(listing::from_nanos(*mtime))?;
+        }
+
+        stats.stop(current_bytes - resumed_from, wire_bytes, peak);
+        Ok(stats)
     }
 
-    pub fn listen(&mut self, stream: &mut TcpStream) {
+    // Tells the sender which blocks of the file we already have
+    pub fn send_signature(&self, file_name: &str, stream: &mut TcpStream) -> io::Result<()> {
+        let signature = delta::Signature::of_path(Path::new(file_name))?;
+        let header = create::signature(signature.block_size, signature.basis_len, signature.blocks.len() as u32);
+        stream.write_all(&header)?;
+        stream.write_all(&signature.blocks_to_bytes())
+    }
+
+    // A redirected file is saved to dest, or to the name the sender gives if there is none
+    // A download that ends without a file fails with whatever the server said instead
+    pub fn listen(&mut self, stream: &mut TcpStream, dest: Option<&str>) -> io::Result<Option<stats::TransferStats>> {
         let mut buf = [0; net::PACKET_SIZE];
+        let mut message = String::new();
+        let mut received = None;
 
         loop {
-            stream.peek(&mut buf).expect("Unable to peek stream");
-            let command = parse::packet(&buf) as u8;
-
-            if command == (net::Code::Redirect as u8) {
-                stream.read_exact(&mut buf).unwrap();
-                let (port, filename) = parse::redirect(buf);
-                let stats = self.get_file(&filename, port, stream);
-                println!("{}", stats);
-                break;
-            }
-            else if command == (net::Code::Stdout as u8) {
-                stream.read_exact(&mut buf).unwrap();
-                let s = String::from_utf8_lossy(&buf[1..]);
-                print!("{}", s);
-            }
-            else if command == (net::Code::End as u8) {
-                stream.read_exact(&mut buf).unwrap();
-                break;
+            stream.read_exact(&mut buf)?;
+            match parse::packet(&buf) {
+                net::Code::Redirect => {
+                    let (port, filename) = parse::redirect(buf);
+                    received = Some(self.get_file(dest.unwrap_or(&filename), port, stream)?);
+                    break;
+                },
+                net::Code::Stdout if dest.is_some() => {
+                    message.push_str(String::from_utf8_lossy(&buf[1..]).trim_end_matches('\0'));
+                },
+                net::Code::Stdout => {
+                    let s = String::from_utf8_lossy(&buf[1..]);
+                    if crate::output::json() { eprint!("{}", s) } else { print!("{}", s) }
+                },
+                net::Code::End if dest.is_some() => return Err(io::Error::new(io::ErrorKind::NotFound, message)),
+                net::Code::End => break,
+                code => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected {:?} packet", code)))
             }
         }
-        println!();
+        note!("");
+        Ok(received)
+    }
+
+    pub fn read_listing(&self, stream: &mut TcpStream) -> io::Result<Vec<listing::Entry>> {
+        let mut packet = [0; net::PACKET_SIZE];
+        stream.read_exact(&mut packet)?;
+        if parse::packet(&packet) != net::Code::List {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected a listing, got {:?}", parse::packet(&packet))));
+        }
+
+        let (_entries, len) = parse::list(&packet);
+        let mut bytes = Vec::new();
+        stream.take(len).read_to_end(&mut bytes)?;
+        listing::from_bytes(&bytes)
     }
 
-    pub fn delete_file(&self, stream: &mut TcpStream, path: &str) {
+    // The client hears whether it worked, the caller gets the reason when it didn't
+    pub fn delete_file(&self, stream: &mut TcpStream, path: &str) -> io::Result<()> {
         let path = Path::new(path);
-        match std::fs::remove_file(path) {
+        // Only empty directories, like rmdir
+        let result = if path.is_dir() { std::fs::remove_dir(path) } else { std::fs::remove_file(path) };
+        match result {
             Ok(_result) => {
                 let mut packet = [0; net::PACKET_SIZE];
                 packet[0] = net::Code::End as u8;
                 stream.write_all(&packet).expect("Unable to write to stream");
             },
-            Err(_os) => {
+            Err(_) => {
                 let mut packet = [0; net::PACKET_SIZE];
                 packet[0] = net::Code::Stdout as u8;
-                let mut offset = 1;
-               
                 let message = "Unable to delete file";
-                for b in message.as_bytes().iter() {
-                    packet[offset] = *b;
-                    offset += 1
+                for (i, b) in message.as_bytes().iter().enumerate() {
+                    packet[i + 1] = *b;
                 }
                 stream.write_all(&packet).expect("Unable to write to stream");
                 packet[0] = net::Code::End as u8;
                 stream.write_all(&packet).expect("Unable to write to stream");
-                println!("Unable to delete file"); }
+            }
         }
+        result
     }
 }
 
 //Reads from File, writes to TcpStream
 pub struct FileTransmitter {
+    limiters: Vec<Limiter>,
+    codec: Codec,
+    zero_copy: bool
 }
 
-fn get_rate<'a>(bytes: usize) -> (f32, &'a str) {
+pub fn get_rate<'a>(bytes: usize) -> (f32, &'a str) {
     if bytes > 1000000000 {
         (bytes as f32 / 1000000000.0, "GB")
     }
@@ -133,82 +263,322 @@ fn get_rate<'a>(bytes: usize) -> (f32, &'a str) {
     }
 }
 
-fn get_stats_file(name: &str) -> File {
-   File::create(format!("{}.{}", name, "stats")).expect("Failed to open stats file")
+// Bar of one transfer on the terminal, driven by its live rate
+struct Progress {
+    bar: ProgressBar,
+    realtime: stats::RealtimeStats
+}
+
+impl Progress {
+    fn new(size: u64, offset: u64) -> Progress {
+        // The server logs instead
+        let bar = if crate::output::mode() == crate::output::Mode::Log { ProgressBar::hidden() } else { ProgressBar::new(size) };
+        bar.set_style(ProgressStyle::default_bar()
+            .template(" [{bar:30}] {bytes}/{total_bytes} {wide_msg:.green}")
+            .progress_chars("#>-"));
+        bar.set_position(offset);
+        Progress { bar, realtime: stats::RealtimeStats::new(size as usize, offset as usize) }
+    }
+
+    fn add(&mut self, bytes: usize) {
+        if self.realtime.add_bytes(bytes) {
+            let eta = self.realtime.eta().map(format_duration).unwrap_or_else(|| String::from("--"));
+            self.bar.set_message(&format!("{}/s, {} left", format_bytes(self.realtime.rate() as usize), eta));
+        }
+        self.bar.set_position(self.realtime.current_bytes() as u64);
+    }
+
+    // The peak rate, for the transfer's stats
+    fn finish(self) -> f64 {
+        self.bar.finish_and_clear();
+        self.realtime.peak()
+    }
+}
+
+// 1.5 MB, with the units of get_rate
+pub fn format_bytes(bytes: usize) -> String {
+    let (amount, unit) = get_rate(bytes);
+    if unit == "B" { format!("{} B", amount) } else { format!("{:.1} {}", amount, unit) }
+}
+
+// 850 ms, 4.2 s, 1:05 or 2:01:05
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if duration < Duration::from_secs(1) {
+        format!("{} ms", duration.as_millis())
+    }
+    else if secs < 60 {
+        format!("{:.1} s", duration.as_secs_f64())
+    }
+    else if secs < 3600 {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+    else {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+// Hidden sibling of the target, so half written files never take its name
+pub fn part_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.part", name))
+}
+
+// Size of a kept partial file, 0 if there is none
+pub fn partial_len(part: &Path) -> u64 {
+    std::fs::metadata(part).map(|m| m.len()).unwrap_or(0)
+}
+
+// A transfer starting at offset continues the partial file, which has to end right there
+fn open_part(part: &Path, offset: u64) -> io::Result<File> {
+    if offset == 0 {
+        return File::create(part);
+    }
+
+    let file = OpenOptions::new().append(true).open(part)?;
+    if file.metadata()?.len() != offset {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Partial file doesn't match the resume offset"));
+    }
+    Ok(file)
+}
+
+// Fills the buffer unless the file ends first
+fn read_chunk(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(bytes) => filled += bytes,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(filled)
+}
+
+// Hands the file range straight from the page cache to the socket
+#[cfg(target_os = "linux")]
+fn send_file_range(stream: &TcpStream, file: &File, offset: u64, len: usize) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = offset as libc::off_t;
+    let mut remaining = len;
+    while remaining > 0 {
+        let sent = unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, remaining) };
+        if sent < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        else if sent == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shrank during transfer"));
+        }
+        else {
+            remaining -= sent as usize;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_file_range(_stream: &TcpStream, _file: &File, _offset: u64, _len: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "sendfile is only available on Linux"))
+}
+
+impl Default for FileTransmitter {
+    fn default() -> FileTransmitter {
+        FileTransmitter::new()
+    }
 }
 
 impl FileTransmitter {
     pub fn new() -> FileTransmitter {
-        FileTransmitter {}
+        FileTransmitter { limiters: Vec::new(), codec: Codec::None, zero_copy: true }
+    }
+
+    // Uncompressed transfers use sendfile on Linux unless this is turned off
+    pub fn set_zero_copy(&mut self, zero_copy: bool) {
+        self.zero_copy = zero_copy;
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
     }
 
-    pub fn host_file(&mut self, path: &str, stream: &mut TcpStream) -> stats::TransferStats {
-        let path = Path::new(path).canonicalize().expect("Failed to canonicalize path");
-        println!("{:?}", path);
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
 
-        let mut file = File::open(&path).expect("File Error");
-        let size = file.metadata().expect("File Error").len() as u64;
+    pub fn throttle(&mut self, limiter: Limiter) {
+        self.limiters.push(limiter);
+    }
 
-        println!("Hosting file {:?}", &path);
-        println!("Total size: {}", size);
+    fn send_chunk(&self, stream: &mut TcpStream, offset: u64, total: u64, mtime: u64, data: &[u8], codec: Codec) -> io::Result<usize> {
+        let (chunk_codec, wire) = compression::compress(codec, data);
+        let header = create::data(&net::Chunk {
+            id: 0x01,
+            offset,
+            total,
+            codec: chunk_codec as u8,
+            raw_len: data.len() as u32,
+            wire_len: wire.len() as u32,
+            mtime
+        });
+
+        throttle::acquire_all(&self.limiters, header.len() + wire.len());
+        stream.write_all(&header)?;
+        stream.write_all(&wire)?;
+        Ok(header.len() + wire.len())
+    }
 
+    pub fn read_signature(&self, stream: &mut TcpStream) -> io::Result<delta::Signature> {
         let mut packet = [0; net::PACKET_SIZE];
-        packet[0] = net::Code::Data as u8;
-        stream.set_write_timeout(Some(std::time::Duration::new(1, 0))).expect("Unable to set write timeout");
-        let progress = ProgressBar::new(size);
-        progress.set_style(ProgressStyle::default_spinner()
-            .template(" {bytes}/{total_bytes} {wide_msg:.green}")
-            .progress_chars("#>-"));
+        stream.read_exact(&mut packet)?;
+        if parse::packet(&packet) != net::Code::Signature {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected a signature, got {:?}", parse::packet(&packet))));
+        }
+
+        let (block_size, basis_len, count) = parse::signature(&packet);
+        delta::Signature::read(stream, block_size, basis_len, count)
+    }
+
+    // Sends only what the other side's signature says it is missing
+    pub fn host_delta(&mut self, path: &str, signature: &delta::Signature, stream: &mut TcpStream) -> io::Result<stats::TransferStats> {
+        let path = Path::new(path).canonicalize()?;
+        let file = File::open(&path)?;
+        let metadata = file.metadata()?;
+        let size = metadata.len();
+        let mtime = metadata.modified().map(listing::to_nanos).unwrap_or(0);
+        let codec = if compression::is_precompressed(&path) { Codec::None } else { self.codec };
+
+        note!("Hosting delta of {:?} against {} blocks", &path, signature.blocks.len());
 
         let mut stats = stats::TransferStats::new();
-        let mut realtime_stats = stats::RealtimeStats::new();
+        let mut progress = Progress::new(size, 0);
         let mut current_bytes: u64 = 0;
-        let instant = Instant::now();
+        let mut literal_bytes: u64 = 0;
+        let mut wire_bytes = 0;
+        delta::diff(signature, file, |op| {
+            match op {
+                delta::Op::Copy { offset, len } => {
+                    let packet = create::copy(offset, len, current_bytes, size, mtime);
+                    throttle::acquire_all(&self.limiters, packet.len());
+                    stream.write_all(&packet)?;
+                    wire_bytes += packet.len();
+                    current_bytes += len;
+                    progress.add(len as usize);
+                },
+                delta::Op::Literal(data) => {
+                    wire_bytes += self.send_chunk(stream, current_bytes, size, mtime, &data, codec)?;
+                    current_bytes += data.len() as u64;
+                    literal_bytes += data.len() as u64;
+                    progress.add(data.len());
+                }
+            }
+            Ok(())
+        })?;
 
-        let mut last_second = 0;
-        let mut last_bytes = 0;
+        // Empty files still have to announce their size
+        if size == 0 {
+            wire_bytes += self.send_chunk(stream, 0, 0, mtime, &[], codec)?;
+        }
+        let peak = progress.finish();
 
-        let mut stat_file = get_stats_file(path.file_name().unwrap().to_str().unwrap());
-        loop {
-            if instant.elapsed().as_secs() != last_second {
-                let bytes = current_bytes - last_bytes;
-                stat_file.write_all(format!("{}\n", bytes).as_bytes()).expect("Unable to write to stats file");
+        let (literal, literal_name) = get_rate(literal_bytes as usize);
+        let (reused, reused_name) = get_rate((current_bytes - literal_bytes) as usize);
+        note!("Sent {} {} of changes, reused {} {}", literal, literal_name, reused, reused_name);
 
-                let (bytes, rate) = get_rate(bytes as usize);
+        stats.stop(current_bytes as usize, wire_bytes, peak);
+        Ok(stats)
+    }
 
-                progress.set_message(&format!("[Transfer Rate: {} {}]", bytes, rate));
-                progress.inc(1);
-                progress.set_position(current_bytes);
+    pub fn host_file(&mut self, path: &str, stream: &mut TcpStream) -> io::Result<stats::TransferStats> {
+        self.host_file_from(path, 0, stream)
+    }
 
-                last_second = instant.elapsed().as_secs();
-                last_bytes = current_bytes;
-            }
-            let bytes = file.read(&mut packet[net::DATA_OFFSET..]);
-            realtime_stats.set_size(size as usize);
+    // Sends the file from offset on, for receivers that kept part of it
+    pub fn host_file_from(&mut self, path: &str, offset: u64, stream: &mut TcpStream) -> io::Result<stats::TransferStats> {
+        let path = Path::new(path).canonicalize()?;
+        note!("{:?}", path);
+
+        let mut file = File::open(&path)?;
+        let metadata = file.metadata()?;
+        let size = metadata.len();
+        let mtime = metadata.modified().map(listing::to_nanos).unwrap_or(0);
+        let offset = if offset > size { 0 } else { offset };
+        file.seek(SeekFrom::Start(offset))?;
+
+        note!("Hosting file {:?}", &path);
+        note!("Total size: {}", size);
+        if offset > 0 {
+            note!("Resuming from {}", offset);
+        }
 
-            match bytes {
-                Ok(bytes) => {
-                    if bytes != 0 {
-                        //println!("\t{}/{}", current_bytes, size);
-                        net::mod_data(&mut packet, 0x01, current_bytes, size);
+        let codec = if compression::is_precompressed(&path) { Codec::None } else { self.codec };
+        if codec != Codec::None {
+            note!("Compression: {}", codec.name());
+        }
 
-                        while let Err(..) = stream.write_all(&packet) {
-                            println!("Timing out...");
-                        }
+        let zero_copy = self.zero_copy && codec == Codec::None && cfg!(target_os = "linux");
+        let mut buf = if zero_copy { Vec::new() } else { vec![0; net::CHUNK_SIZE] };
+        let mut stats = stats::TransferStats::new();
+        let mut progress = Progress::new(size, offset);
+        let mut current_bytes: u64 = offset;
+        let mut wire_bytes = 0;
 
-                        //stream.write_all(&packet).expect("Network error");
-                        current_bytes += bytes as u64;
-                        realtime_stats.add_bytes(bytes);
-                    }
-                    else {
-                        break;
-                    }
-                },
-                Err(_e) => {}
+        loop {
+            // Always send at least one chunk so empty files still announce their size
+            let bytes = if zero_copy {
+                // A whole chunk on a slow limit would keep the receiver waiting past its timeout
+                let slice = throttle::slice(&self.limiters, net::ZERO_COPY_CHUNK_SIZE);
+                let bytes = (size - current_bytes).min(slice as u64) as usize;
+                let header = create::data(&net::Chunk {
+                    id: 0x01,
+                    offset: current_bytes,
+                    total: size,
+                    codec: Codec::None as u8,
+                    raw_len: bytes as u32,
+                    wire_len: bytes as u32,
+                    mtime
+                });
+
+                throttle::acquire_all(&self.limiters, header.len() + bytes);
+                stream.write_all(&header)?;
+                send_file_range(stream, &file, current_bytes, bytes)?;
+
+                wire_bytes += header.len() + bytes;
+                bytes
+            }
+            else {
+                let bytes = read_chunk(&mut file, &mut buf)?;
+                wire_bytes += self.send_chunk(stream, current_bytes, size, mtime, &buf[..bytes], codec)?;
+                bytes
+            };
+
+            current_bytes += bytes as u64;
+            progress.add(bytes);
+
+            if bytes == 0 || current_bytes >= size {
+                break;
             }
         }
-        stats.stop(current_bytes as usize);
-        stats
+        let peak = progress.finish();
+        stats.stop((current_bytes - offset) as usize, wire_bytes, peak);
+        Ok(stats)
+    }
+
+    pub fn send_listing(&self, path: &str, flags: u8, stream: &mut TcpStream) -> io::Result<()> {
+        let hash = flags & net::LIST_HASH != 0;
+        let entries = if flags & net::LIST_SHALLOW != 0 { listing::scan_shallow(Path::new(path), hash) } else { listing::scan(Path::new(path), hash) };
+        let entries = entries.unwrap_or_else(|e| {
+            tracing::warn!("Unable to list {}: {}", path, e);
+            Vec::new()
+        });
+        let bytes = listing::to_bytes(&entries);
+
+        stream.write_all(&create::list(entries.len() as u32, bytes.len() as u64))?;
+        stream.write_all(&bytes)
     }
 
     pub fn dir(&self, path: &str, stream: &mut TcpStream) {
@@ -237,7 +607,6 @@ impl FileTransmitter {
                         }
                     }
                     else {
-                        path.len();
                         for b in path.as_bytes().iter() {
                             packet[offset] = *b;
                             offset += 1
@@ -0,0 +1,105 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+// A change made through the server, paths are relative to the served directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Create(String),
+    Modify(String),
+    Delete(String),
+    Rename(String, String)
+}
+
+impl Event {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Event::Create(_) => 0x1,
+            Event::Modify(_) => 0x2,
+            Event::Delete(_) => 0x3,
+            Event::Rename(_, _) => 0x4
+        }
+    }
+
+    pub fn from_parts(kind: u8, path: String, to: String) -> Option<Event> {
+        match kind {
+            0x1 => Some(Event::Create(path)),
+            0x2 => Some(Event::Modify(path)),
+            0x3 => Some(Event::Delete(path)),
+            0x4 => Some(Event::Rename(path, to)),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Create(_) => "create",
+            Event::Modify(_) => "modify",
+            Event::Delete(_) => "delete",
+            Event::Rename(_, _) => "rename"
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            Event::Create(path) | Event::Modify(path) | Event::Delete(path) | Event::Rename(path, _) => path
+        }
+    }
+
+    // New name of a renamed file, empty otherwise
+    pub fn to(&self) -> &str {
+        match self {
+            Event::Rename(_, to) => to,
+            _ => ""
+        }
+    }
+
+    // Whether a watch on dir should hear about this, an empty dir watches everything
+    pub fn within(&self, dir: &str) -> bool {
+        let inside = |path: &str| dir.is_empty() || path == dir || path.starts_with(&format!("{}/", dir));
+        inside(self.path()) || (!self.to().is_empty() && inside(self.to()))
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Create(path) => write!(f, "created  {}", path),
+            Event::Modify(path) => write!(f, "modified {}", path),
+            Event::Delete(path) => write!(f, "deleted  {}", path),
+            Event::Rename(from, to) => write!(f, "renamed  {} -> {}", from, to)
+        }
+    }
+}
+
+// Fans events out to every watching connection
+#[derive(Clone, Default)]
+pub struct Hub {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>
+}
+
+impl Hub {
+    pub fn new() -> Hub {
+        Hub::default()
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    // Watchers that went away are dropped here
+    pub fn publish(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+// Turns a resolved server path like ./a/b back into a/b
+pub fn relative(path: &str) -> String {
+    match path.strip_prefix("./") {
+        Some(rest) => String::from(rest),
+        None if path == "." => String::new(),
+        None => String::from(path)
+    }
+}
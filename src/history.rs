@@ -0,0 +1,205 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use crate::audit::FileInfo;
+use crate::encoding::{format_bytes, format_duration};
+use crate::stats::{Outcome, TransferStats};
+
+// One operation as the client saw it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub server: String,
+    // upload, download, delete or rename
+    pub direction: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local: Option<String>,
+    pub remote: String,
+    // Where a rename moved it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub hash: Option<String>,
+    pub duration_secs: f64,
+    pub result: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+impl Entry {
+    fn paths(&self) -> String {
+        match (self.direction.as_str(), self.local.as_ref(), self.to.as_ref()) {
+            ("upload", Some(local), _) => format!("{} -> {}", local, self.remote),
+            (_, Some(local), _) => format!("{} -> {}", self.remote, local),
+            (_, None, Some(to)) => format!("{} -> {}", self.remote, to),
+            _ => self.remote.clone()
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = self.size.map(|size| format_bytes(size as usize)).unwrap_or_default();
+        write!(f, "{}  {:<8} {:<6} {:>9}  {}  ({}, {}", self.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"), self.direction,
+            self.result, size, self.paths(), self.server, format_duration(Duration::from_secs_f64(self.duration_secs)))?;
+        if let Some(hash) = self.hash.as_ref() {
+            write!(f, ", blake3 {}", &hash[..hash.len().min(12)])?;
+        }
+        write!(f, ")")?;
+        if let Some(error) = self.error.as_ref() {
+            write!(f, "\n    {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+struct History {
+    file: File,
+    server: String
+}
+
+static HISTORY: Mutex<Option<History>> = Mutex::new(None);
+
+// Next to the shell's command history
+pub fn default_path() -> Option<PathBuf> {
+    Some(dirs::data_dir()?.join("netfolder").join("transfers.jsonl"))
+}
+
+// Everything recorded from now on is marked as done on server
+pub fn open(path: &Path, server: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *HISTORY.lock().unwrap() = Some(History { file, server: server.to_string() });
+    Ok(())
+}
+
+fn write(mut entry: Entry) {
+    let mut history = HISTORY.lock().unwrap();
+    let history = match history.as_mut() {
+        Some(history) => history,
+        None => return
+    };
+    entry.server = history.server.clone();
+    let mut line = serde_json::to_string(&entry).expect("History entries always serialize");
+    line.push('\n');
+    if let Err(e) = history.file.write_all(line.as_bytes()) {
+        eprintln!("Unable to write to the transfer history: {}", e);
+    }
+}
+
+fn enabled() -> bool {
+    HISTORY.lock().unwrap().is_some()
+}
+
+// The local file is hashed as it is now, which after a transfer is what was sent or received
+pub fn transfer(direction: &str, local: &str, remote: &str, stats: &TransferStats) {
+    if !enabled() {
+        return;
+    }
+    let ok = stats.outcome() == Outcome::Completed;
+    let file = if ok { FileInfo::of(Path::new(local)) } else { FileInfo::default() };
+    write(Entry {
+        time: stats.started(),
+        server: String::new(),
+        direction: direction.to_string(),
+        local: Some(local.to_string()),
+        remote: remote.to_string(),
+        to: None,
+        size: file.size,
+        hash: file.hash,
+        duration_secs: stats.elapsed().as_secs_f64(),
+        result: String::from(if ok { "ok" } else { "failed" }),
+        error: stats.error().map(String::from)
+    });
+}
+
+// Deletes and renames, which only have a result
+pub fn operation(direction: &str, remote: &str, to: Option<&str>, duration: Duration, result: &io::Result<()>) {
+    if !enabled() {
+        return;
+    }
+    write(Entry {
+        time: Utc::now() - chrono::Duration::from_std(duration).unwrap_or_default(),
+        server: String::new(),
+        direction: direction.to_string(),
+        local: None,
+        remote: remote.to_string(),
+        to: to.map(String::from),
+        size: None,
+        hash: None,
+        duration_secs: duration.as_secs_f64(),
+        result: String::from(if result.is_ok() { "ok" } else { "failed" }),
+        error: result.as_ref().err().map(|e| e.to_string())
+    });
+}
+
+// Oldest first, lines that can't be read, like one cut off by a crash, are left out
+pub fn read(path: &Path) -> io::Result<Vec<Entry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+// A date, a local date and time, or how long ago like 30m, 12h or 7d
+pub fn parse_since(since: &str) -> io::Result<DateTime<Utc>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid time {}, expected something like 2024-05-01, 2024-05-01T18:00 or 12h", since));
+    let since = since.trim();
+
+    let units = [('s', 1), ('m', 60), ('h', 3600), ('d', 86400), ('w', 604800)];
+    if let Some((unit, seconds)) = units.iter().find(|(unit, _)| since.ends_with(*unit)) {
+        if let Ok(amount) = since.trim_end_matches(*unit).parse::<i64>() {
+            return Ok(Utc::now() - chrono::Duration::seconds(amount * seconds));
+        }
+    }
+
+    let local = NaiveDateTime::parse_from_str(since, "%Y-%m-%dT%H:%M")
+        .or_else(|_| NaiveDateTime::parse_from_str(since, "%Y-%m-%d %H:%M"))
+        .or_else(|_| NaiveDate::parse_from_str(since, "%Y-%m-%d").map(|date| date.and_hms_opt(0, 0, 0).unwrap()))
+        .map_err(|_| invalid())?;
+    Local.from_local_datetime(&local).earliest().map(|time| time.with_timezone(&Utc)).ok_or_else(invalid)
+}
+
+// Entries have to match everything that's given
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub server: Option<String>,
+    pub direction: Option<String>,
+    // Matched against the whole path and the file name, on either side
+    pub path: Option<glob::Pattern>,
+    pub since: Option<DateTime<Utc>>,
+    pub failed: bool
+}
+
+impl Filter {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        let path_matches = |pattern: &glob::Pattern| {
+            [Some(&entry.remote), entry.local.as_ref(), entry.to.as_ref()].iter().flatten().any(|path| {
+                let name = Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or(path);
+                pattern.matches(path) || pattern.matches(name)
+            })
+        };
+        self.server.as_ref().is_none_or(|server| entry.server.contains(server.as_str()))
+            && self.direction.as_ref().is_none_or(|direction| entry.direction == *direction)
+            && self.path.as_ref().is_none_or(path_matches)
+            && self.since.is_none_or(|since| entry.time >= since)
+            && (!self.failed || entry.result != "ok")
+    }
+}
@@ -0,0 +1,17 @@
+#[macro_use]
+pub mod output;
+pub mod net;
+pub mod stats;
+pub mod encoding;
+pub mod throttle;
+pub mod compression;
+pub mod delta;
+pub mod listing;
+pub mod sync;
+pub mod events;
+pub mod watcher;
+pub mod retry;
+pub mod config;
+pub mod audit;
+pub mod metrics;
+pub mod history;
@@ -0,0 +1,147 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Serialize, Serializer};
+
+pub const HASH_SIZE: usize = 32;
+// Kind, size, mtime, hash and path length before the path itself
+const ENTRY_HEADER_SIZE: usize = 1 + 8 + 8 + HASH_SIZE + 2;
+
+// A file or directory under a listed root, paths always use '/'
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Entry {
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    // Nanoseconds since the epoch, 0 if unknown
+    pub mtime: u64,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "hex")]
+    pub hash: Option<[u8; HASH_SIZE]>
+}
+
+fn hex<S: Serializer>(hash: &Option<[u8; HASH_SIZE]>, serializer: S) -> Result<S::Ok, S::Error> {
+    hash.map(|hash| hash.iter().map(|b| format!("{:02x}", b)).collect::<String>()).serialize(serializer)
+}
+
+pub fn to_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+pub fn from_nanos(nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos)
+}
+
+pub fn hash_file(path: &Path) -> io::Result<[u8; HASH_SIZE]> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+// Two-way sync bookkeeping, kept in the synced directory itself
+pub const SYNC_STATE: &str = ".netfolder-sync";
+
+// Half written transfers, see encoding::part_path
+pub fn is_partial(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".part")
+}
+
+// Files that belong to netfolder rather than the user
+pub fn is_ignored(name: &str) -> bool {
+    is_partial(name) || name.starts_with(SYNC_STATE)
+}
+
+// Recursively lists everything under root, a missing root lists as empty
+pub fn scan(root: &Path, hash: bool) -> io::Result<Vec<Entry>> {
+    scan_depth(root, hash, true)
+}
+
+// Just the entries of root itself
+pub fn scan_shallow(root: &Path, hash: bool) -> io::Result<Vec<Entry>> {
+    scan_depth(root, hash, false)
+}
+
+fn scan_depth(root: &Path, hash: bool, recursive: bool) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    if root.is_dir() {
+        scan_dir(root, "", hash, recursive, &mut entries)?;
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+fn scan_dir(dir: &Path, prefix: &str, hash: bool, recursive: bool, entries: &mut Vec<Entry>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if is_ignored(&name) {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let path = format!("{}{}", prefix, name);
+        let mtime = metadata.modified().map(to_nanos).unwrap_or(0);
+
+        if metadata.is_dir() {
+            entries.push(Entry { path: path.clone(), is_dir: true, size: 0, mtime, hash: None });
+            if recursive {
+                scan_dir(&entry.path(), &format!("{}/", path), hash, recursive, entries)?;
+            }
+        }
+        else if metadata.is_file() {
+            let hash = if hash { Some(hash_file(&entry.path())?) } else { None };
+            entries.push(Entry { path, is_dir: false, size: metadata.len(), mtime, hash });
+        }
+    }
+    Ok(())
+}
+
+pub fn to_bytes(entries: &[Entry]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for entry in entries.iter() {
+        let mut header = [0; ENTRY_HEADER_SIZE];
+        header[0] = (entry.is_dir as u8) | ((entry.hash.is_some() as u8) << 1);
+        LittleEndian::write_u64(&mut header[1..9], entry.size);
+        LittleEndian::write_u64(&mut header[9..17], entry.mtime);
+        if let Some(hash) = entry.hash {
+            header[17..17 + HASH_SIZE].copy_from_slice(&hash);
+        }
+        LittleEndian::write_u16(&mut header[17 + HASH_SIZE..], entry.path.len() as u16);
+
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(entry.path.as_bytes());
+    }
+    bytes
+}
+
+pub fn from_bytes(bytes: &[u8]) -> io::Result<Vec<Entry>> {
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "Truncated listing");
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let header = bytes.get(offset..offset + ENTRY_HEADER_SIZE).ok_or_else(truncated)?;
+        let path_len = LittleEndian::read_u16(&header[17 + HASH_SIZE..]) as usize;
+        let path = bytes.get(offset + ENTRY_HEADER_SIZE..offset + ENTRY_HEADER_SIZE + path_len).ok_or_else(truncated)?;
+
+        let hash = if header[0] & 0x2 != 0 {
+            let mut hash = [0; HASH_SIZE];
+            hash.copy_from_slice(&header[17..17 + HASH_SIZE]);
+            Some(hash)
+        }
+        else {
+            None
+        };
+
+        entries.push(Entry {
+            path: String::from_utf8_lossy(path).into_owned(),
+            is_dir: header[0] & 0x1 != 0,
+            size: LittleEndian::read_u64(&header[1..9]),
+            mtime: LittleEndian::read_u64(&header[9..17]),
+            hash
+        });
+        offset += ENTRY_HEADER_SIZE + path_len;
+    }
+    Ok(entries)
+}
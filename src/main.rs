@@ -1,13 +1,9 @@
 use clap::{App, Arg};
-//use net::{server, client};
-pub mod net;
-pub mod stats;
-pub mod encoding;
-//
+use netfolder::net;
 
 macro_rules! arg {
     ($t:expr) => {
-        Arg::new($t).long($t);
+        Arg::new($t).long($t)
     };
 }
 
@@ -17,44 +13,231 @@ fn main() {
         .author("Jackson Codispoti <jackson.codispoti@uky.edu>")
         .about("Connect to another PC and transfer files")
         .subcommand(App::new("server")
+                    .arg(arg!("port")
+                         .short('p')
+                         .takes_value(true)
+                         .about("The port to listen on"))
+                    .arg(arg!("limit")
+                         .takes_value(true)
+                         .about("Total bandwidth cap across all connections, e.g. 50M"))
+                    .arg(arg!("conn-limit")
+                         .takes_value(true)
+                         .about("Bandwidth cap for each connection, e.g. 10M"))
+                    .arg(arg!("log-level")
+                         .takes_value(true)
+                         .default_value("info")
+                         .about("Least important log messages to print, or a filter like netfolder=debug"))
+                    .arg(arg!("log-json")
+                         .takes_value(false)
+                         .about("Print log messages as JSON lines"))
+                    .arg(arg!("audit-log")
+                         .takes_value(true)
+                         .about("Append a JSON line for every upload, download, delete and rename to this file"))
+                    .arg(arg!("stats-file")
+                         .takes_value(true)
+                         .about("Keep the server statistics in this file across restarts"))
+                    .arg(arg!("metrics")
+                         .takes_value(true)
+                         .about("Serve Prometheus metrics at /metrics on this port, or address:port to listen beyond localhost"))
                     .about("Launch a server")
                     .version("0.0.1")
                     .author("Jackson Codispoti <jackson.codispoti@uky.edu>"))
         .subcommand(App::new("client")
+                    .arg(Arg::new("at-profile")
+                         .index(1)
+                         .conflicts_with("profile")
+                         .about("A profile from the config file, as @name"))
+                    .arg(arg!("profile")
+                         .takes_value(true)
+                         .about("A profile from the config file"))
+                    .arg(arg!("config")
+                         .takes_value(true)
+                         .about("The config file, ~/.config/netfolder/config.toml by default"))
                     .arg(arg!("host")
                          .short('n')
                          .takes_value(true)
-                         .about("The hostname to connect to"))
+                         .about("The server to connect to, as host, host:port or [v6 address]:port"))
                     .arg(arg!("port")
                          .short('p')
                          .takes_value(true)
-                         .about("The port to connect to"))
+                         .about("The port to connect to, 3219 if the host doesn't give one"))
 
                     .arg(arg!("upload")
                          .short('u')
                          .takes_value(true)
-                         .about("The file to upload"))
+                         .about("The file to upload, or a quoted pattern like '*.log'"))
                     .arg(arg!("download")
                          .short('d')
                          .takes_value(true)
-                         .about("The file to download"))
+                         .about("The file to download, or a quoted pattern like 'reports/*.csv'"))
+                    .arg(arg!("delta")
+                         .takes_value(false)
+                         .about("Only send the parts of the file that changed"))
                     .arg(arg!("delete")
                          .short('D')
                          .takes_value(true)
-                         .about("The file to delete"))
+                         .about("The file to delete, or a quoted pattern like 'tmp/*'"))
+                    .arg(arg!("yes")
+                         .short('y')
+                         .takes_value(false)
+                         .about("Delete what a pattern matched without asking"))
                     .arg(arg!("list")
                          .short('l')
                          .takes_value(false)
                          .about("List files on server"))
+                    .arg(arg!("transfer-log")
+                         .takes_value(true)
+                         .about("Append the stats of every transfer to a file, as CSV if it ends in .csv and JSON lines otherwise"))
+                    .arg(arg!("history-file")
+                         .takes_value(true)
+                         .about("Where to keep the history of transfers, deletes and renames"))
+                    .arg(arg!("no-history")
+                         .takes_value(false)
+                         .about("Don't add this run to the history"))
+                    .arg(arg!("stats")
+                         .takes_value(false)
+                         .about("Show what the server has done, only from the server's own machine"))
+                    .arg(arg!("limit")
+                         .takes_value(true)
+                         .about("Bandwidth cap for transfers, e.g. 10M"))
+                    .arg(arg!("compress")
+                         .short('z')
+                         .takes_value(true)
+                         .possible_values(&["none", "zstd", "lz4"])
+                         .about("Compress transfers if the server supports it"))
+                    .arg(arg!("retries")
+                         .takes_value(true)
+                         .default_value("3")
+                         .about("How many times to reconnect and retry a failed command"))
+                    .arg(arg!("timeout")
+                         .takes_value(true)
+                         .default_value("30")
+                         .about("Seconds to wait on the server before giving up, 0 waits forever"))
 
+                    .arg(arg!("output")
+                         .takes_value(true)
+                         .possible_values(&["text", "json"])
+                         .default_value("text")
+                         .about("Print results as text, or as one JSON object per line with logs on stderr"))
+                    .arg(arg!("batch")
+                         .short('b')
+                         .takes_value(true)
+                         .about("Run the shell commands in a file, - reads them from stdin"))
                     .arg(Arg::new("shell")
                          .long("shell")
                          .short('s')
                          .takes_value(false)
                          .about("Forces to start in shell mode. Undefined behaviours"))
+                    .subcommand(App::new("sync")
+                                .about("Mirror a local directory to the server, or back with --pull")
+                                .arg(Arg::new("local")
+                                     .required(true)
+                                     .index(1)
+                                     .about("The local directory"))
+                                .arg(Arg::new("remote")
+                                     .required(true)
+                                     .index(2)
+                                     .about("The directory on the server"))
+                                .arg(arg!("pull")
+                                     .takes_value(false)
+                                     .about("Mirror the server directory into the local one instead"))
+                                .arg(arg!("two-way")
+                                     .takes_value(false)
+                                     .conflicts_with_all(&["pull", "delete", "checksum"])
+                                     .about("Sync edits in both directions, keeping conflicting copies"))
+                                .arg(arg!("delete")
+                                     .takes_value(false)
+                                     .about("Delete files that don't exist in the source"))
+                                .arg(arg!("dry-run")
+                                     .takes_value(false)
+                                     .about("Print what would be done without doing it"))
+                                .arg(arg!("checksum")
+                                     .short('c')
+                                     .takes_value(false)
+                                     .about("Compare file contents instead of size and modification time")))
+                    .subcommand(App::new("push")
+                                .about("Upload new and changed files from a local directory")
+                                .arg(Arg::new("local")
+                                     .required(true)
+                                     .index(1)
+                                     .about("The local directory"))
+                                .arg(Arg::new("remote")
+                                     .index(2)
+                                     .about("The directory on the server, the served directory by default"))
+                                .arg(arg!("watch")
+                                     .takes_value(false)
+                                     .about("Keep running and upload files as they change")))
+                    .subcommand(App::new("history")
+                                .about("List past transfers, deletes and renames, oldest first")
+                                .arg(arg!("server")
+                                     .takes_value(true)
+                                     .about("Only operations on servers whose host:port contains this"))
+                                .arg(arg!("direction")
+                                     .takes_value(true)
+                                     .possible_values(&["upload", "download", "delete", "rename"])
+                                     .about("Only this kind of operation"))
+                                .arg(arg!("path")
+                                     .takes_value(true)
+                                     .about("Only paths or file names matching this, e.g. 'report*.csv'"))
+                                .arg(arg!("since")
+                                     .takes_value(true)
+                                     .about("Only operations after a date, a local time like 2024-05-01T18:00, or a time ago like 12h or 7d"))
+                                .arg(arg!("failed")
+                                     .takes_value(false)
+                                     .about("Only operations that failed"))
+                                .arg(arg!("last")
+                                     .takes_value(true)
+                                     .about("Only the most recent this many")))
+                    .subcommand(App::new("watch")
+                                .about("Print changes made on the server as they happen")
+                                .arg(Arg::new("path")
+                                     .index(1)
+                                     .about("Only report changes under this directory")))
                     .about("Launch a client")
                     .version("0.0.1")
                     .author("Jackson Codispoti <jackson.codispoti@uky.edu>"))
+        .subcommand(App::new("bench")
+                    .arg(arg!("host")
+                         .short('n')
+                         .takes_value(true)
+                         .about("Measure this server instead of one started on loopback"))
+                    .arg(arg!("port")
+                         .short('p')
+                         .takes_value(true)
+                         .about("The port of the server, 3219 if the host doesn't give one"))
+                    .arg(arg!("size")
+                         .short('s')
+                         .takes_value(true)
+                         .default_value("16M")
+                         .about("Size of each file, e.g. 1K or 256M"))
+                    .arg(arg!("count")
+                         .takes_value(true)
+                         .default_value("8")
+                         .about("Files each connection transfers in each direction"))
+                    .arg(arg!("concurrency")
+                         .short('c')
+                         .takes_value(true)
+                         .default_value("1")
+                         .about("Connections transferring at once"))
+                    .arg(arg!("direction")
+                         .takes_value(true)
+                         .possible_values(&["upload", "download", "both"])
+                         .default_value("both")
+                         .about("Which way to send the files, downloads fetch what was uploaded first"))
+                    .arg(arg!("compress")
+                         .short('z')
+                         .takes_value(true)
+                         .possible_values(&["none", "zstd", "lz4"])
+                         .default_value("none")
+                         .about("Compression to use, the files are random so it only costs time"))
+                    .arg(arg!("output")
+                         .takes_value(true)
+                         .possible_values(&["text", "json", "csv"])
+                         .default_value("text")
+                         .about("Print the results as text, JSON lines or CSV for comparing builds"))
+                    .about("Measure transfer throughput, latency and CPU use")
+                    .version("0.0.1")
+                    .author("Jackson Codispoti <jackson.codispoti@uky.edu>"))
                     .get_matches();
 
     if let Some(server_matches) = matches.subcommand_matches("server") {
@@ -65,7 +248,10 @@ fn main() {
         net::client::start_client(client_matches);
         //println!("Running the client");
     }
+    else if let Some(bench_matches) = matches.subcommand_matches("bench") {
+        net::bench::start_bench(bench_matches);
+    }
     else {
-        println!("Please specify server or client");
+        println!("Please specify server, client or bench");
     }
 }
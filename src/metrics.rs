@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::stats::{ServerStats, Summary, TransferStats};
+
+pub const OPERATIONS: [&str; 4] = ["upload", "download", "delete", "rename"];
+
+// Upper bounds in seconds, everything slower only shows up in +Inf
+const BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0, 60.0, 300.0];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= *le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    active: AtomicU64,
+    connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    wire_bytes_in: AtomicU64,
+    wire_bytes_out: AtomicU64,
+    started: [AtomicU64; OPERATIONS.len()],
+    completed: [AtomicU64; OPERATIONS.len()],
+    failed: [AtomicU64; OPERATIONS.len()],
+    durations: Mutex<[Histogram; OPERATIONS.len()]>,
+    errors: Mutex<BTreeMap<&'static str, u64>>
+}
+
+// Server-wide totals, every connection adds to the same counters
+// The stats keep what outlives the process on top of them, per user and per path
+#[derive(Clone)]
+pub struct Metrics {
+    counters: Arc<Counters>,
+    stats: ServerStats
+}
+
+// Counts a connection as active for as long as it's held
+pub struct ActiveConnection {
+    metrics: Metrics
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.metrics.counters.active.fetch_sub(1, Ordering::Relaxed);
+        self.metrics.stats.disconnected();
+    }
+}
+
+fn operation(name: &str) -> Option<usize> {
+    OPERATIONS.iter().position(|op| *op == name)
+}
+
+impl Metrics {
+    pub fn new(stats: ServerStats) -> Metrics {
+        Metrics { counters: Arc::default(), stats }
+    }
+
+    pub fn connection(&self) -> ActiveConnection {
+        self.counters.connections.fetch_add(1, Ordering::Relaxed);
+        self.counters.active.fetch_add(1, Ordering::Relaxed);
+        self.stats.connected();
+        ActiveConnection { metrics: self.clone() }
+    }
+
+    pub fn started(&self, op: &str) {
+        if let Some(i) = operation(op) {
+            self.counters.started[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Only requests that got as far as a file count against its path
+    pub fn finished(&self, op: &str, user: &str, path: Option<&str>, duration: Duration, ok: bool) {
+        self.stats.finished(user, path, op, ok);
+        let i = match operation(op) {
+            Some(i) => i,
+            None => return
+        };
+        if ok {
+            self.counters.completed[i].fetch_add(1, Ordering::Relaxed);
+        }
+        else {
+            self.counters.failed[i].fetch_add(1, Ordering::Relaxed);
+            self.error("transfer");
+        }
+        self.counters.durations.lock().unwrap()[i].observe(duration.as_secs_f64());
+    }
+
+    pub fn received(&self, user: &str, path: &str, stats: &TransferStats) {
+        self.stats.received(user, path, stats);
+        self.counters.bytes_in.fetch_add(stats.bytes() as u64, Ordering::Relaxed);
+        self.counters.wire_bytes_in.fetch_add(stats.wire_bytes() as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, user: &str, path: &str, stats: &TransferStats) {
+        self.stats.sent(user, path, stats);
+        self.counters.bytes_out.fetch_add(stats.bytes() as u64, Ordering::Relaxed);
+        self.counters.wire_bytes_out.fetch_add(stats.wire_bytes() as u64, Ordering::Relaxed);
+    }
+
+    pub fn error(&self, kind: &'static str) {
+        *self.counters.errors.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    pub fn summary(&self) -> Summary {
+        self.stats.snapshot()
+    }
+
+    // The Prometheus text format
+    pub fn render(&self) -> String {
+        let c = &self.counters;
+        let mut out = String::new();
+        let mut single = |name: &str, kind: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value).unwrap();
+        };
+        single("netfolder_connections_active", "gauge", "Connections currently open", c.active.load(Ordering::Relaxed));
+        single("netfolder_connections_total", "counter", "Connections accepted", c.connections.load(Ordering::Relaxed));
+        single("netfolder_received_bytes_total", "counter", "File bytes received in uploads", c.bytes_in.load(Ordering::Relaxed));
+        single("netfolder_sent_bytes_total", "counter", "File bytes sent in downloads", c.bytes_out.load(Ordering::Relaxed));
+        single("netfolder_received_wire_bytes_total", "counter", "Bytes received in uploads, headers and compression included", c.wire_bytes_in.load(Ordering::Relaxed));
+        single("netfolder_sent_wire_bytes_total", "counter", "Bytes sent in downloads, headers and compression included", c.wire_bytes_out.load(Ordering::Relaxed));
+
+        for (name, help, values) in [
+            ("netfolder_operations_started_total", "Requests started", &c.started),
+            ("netfolder_operations_completed_total", "Requests that succeeded", &c.completed),
+            ("netfolder_operations_failed_total", "Requests that failed", &c.failed)
+        ] {
+            writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name).unwrap();
+            for (op, value) in OPERATIONS.iter().zip(values.iter()) {
+                writeln!(out, "{}{{operation=\"{}\"}} {}", name, op, value.load(Ordering::Relaxed)).unwrap();
+            }
+        }
+
+        let name = "netfolder_operation_duration_seconds";
+        writeln!(out, "# HELP {} How long requests took\n# TYPE {} histogram", name, name).unwrap();
+        for (op, histogram) in OPERATIONS.iter().zip(c.durations.lock().unwrap().iter()) {
+            for (le, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                writeln!(out, "{}_bucket{{operation=\"{}\",le=\"{}\"}} {}", name, op, le, count).unwrap();
+            }
+            writeln!(out, "{}_bucket{{operation=\"{}\",le=\"+Inf\"}} {}", name, op, histogram.count).unwrap();
+            writeln!(out, "{}_sum{{operation=\"{}\"}} {}", name, op, histogram.sum).unwrap();
+            writeln!(out, "{}_count{{operation=\"{}\"}} {}", name, op, histogram.count).unwrap();
+        }
+
+        let name = "netfolder_errors_total";
+        writeln!(out, "# HELP {} Failed requests and connection problems\n# TYPE {} counter", name, name).unwrap();
+        for (kind, count) in c.errors.lock().unwrap().iter() {
+            writeln!(out, "{}{{kind=\"{}\"}} {}", name, kind, count).unwrap();
+        }
+        out
+    }
+
+    // Answers GET /metrics on its own thread until the process exits
+    pub fn serve(&self, addr: SocketAddr) -> io::Result<()> {
+        let server = tiny_http::Server::http(addr).map_err(|e| io::Error::other(e.to_string()))?;
+        let metrics = self.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = if request.url() == "/metrics" {
+                    let header = tiny_http::Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+                    tiny_http::Response::from_string(metrics.render()).with_header(header)
+                }
+                else {
+                    tiny_http::Response::from_string("Not found\n").with_status_code(404)
+                };
+                request.respond(response).ok();
+            }
+        });
+        Ok(())
+    }
+}
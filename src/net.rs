@@ -1,11 +1,20 @@
 pub mod server;
 pub mod client;
+pub mod bench;
 
-use std::net::{self, TcpStream};
-use std::mem;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
 
 pub const PACKET_SIZE: usize = 512;
-pub const DATA_OFFSET: usize = 19;
+pub const DEFAULT_PORT: u16 = 3219;
+// Largest file chunk following a data packet
+pub const CHUNK_SIZE: usize = 64 * 1024;
+// Chunks sent with sendfile skip our buffer, so they can be larger
+pub const ZERO_COPY_CHUNK_SIZE: usize = 1024 * 1024;
+// Receivers refuse anything bigger than this
+pub const MAX_CHUNK_SIZE: usize = ZERO_COPY_CHUNK_SIZE;
 
 #[derive(Debug)]
 #[derive(Copy)]
@@ -23,7 +32,19 @@ pub enum Code {
     Data=0x9,
     Stdout=0xa,
     End=0xb,
-    Disconnect=0xc
+    Disconnect=0xc,
+    Limit=0xd,
+    Hello=0xe,
+    DeltaUpload=0xf,
+    DeltaDownload=0x10,
+    Signature=0x11,
+    Copy=0x12,
+    List=0x13,
+    Watch=0x14,
+    Event=0x15,
+    Rename=0x16,
+    Resume=0x17,
+    Stats=0x18
 }
 
 impl Code {
@@ -47,13 +68,66 @@ impl Code {
             0xa => Code::Stdout,
             0xb => Code::End,
             0xc => Code::Disconnect,
+            0xd => Code::Limit,
+            0xe => Code::Hello,
+            0xf => Code::DeltaUpload,
+            0x10 => Code::DeltaDownload,
+            0x11 => Code::Signature,
+            0x12 => Code::Copy,
+            0x13 => Code::List,
+            0x14 => Code::Watch,
+            0x15 => Code::Event,
+            0x16 => Code::Rename,
+            0x17 => Code::Resume,
+            0x18 => Code::Stats,
             _ => Code::Unknown
         }
     }
 }
 
+// Header of a file chunk, the wire_len chunk bytes follow the packet
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Chunk {
+    pub id: u16,
+    pub offset: u64,
+    pub total: u64,
+    pub codec: u8,
+    pub raw_len: u32,
+    pub wire_len: u32,
+    // Modification time of the file in nanoseconds since the epoch, 0 if unknown
+    pub mtime: u64
+}
+
+// Flags of a List request
+pub const LIST_HASH: u8 = 0x1;
+// Only the directory itself, not what is below it
+pub const LIST_SHALLOW: u8 = 0x2;
+
 pub mod create {
-    use crate::net::{Code, PACKET_SIZE};
+    use byteorder::{ByteOrder, LittleEndian};
+    use crate::net::{Chunk, Code, PACKET_SIZE};
+    use crate::events::Event;
+    use std::io;
+
+    // Two names share a packet, the first one's length comes first
+    fn write_pair(packet: &mut [u8], first: &str, second: &str) -> io::Result<()> {
+        if 2 + first.len() + second.len() > packet.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Names too long for one packet: {} and {}", first, second)));
+        }
+        LittleEndian::write_u16(&mut packet[..2], first.len() as u16);
+        for (i, c) in first.bytes().chain(second.bytes()).enumerate() {
+            packet[2 + i] = c;
+        }
+        Ok(())
+    }
+
+    fn write_name(packet: &mut [u8], name: &str) -> io::Result<()> {
+        if name.len() > packet.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Name too long for one packet: {}", name)));
+        }
+        packet[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(())
+    }
 
     pub fn upload(file_name: &str, id: u16) -> [u8; PACKET_SIZE] {
         let mut packet = Code::Upload.packet();
@@ -67,13 +141,15 @@ pub mod create {
         packet
     }
 
-    pub fn download(file_name: &str) -> [u8; PACKET_SIZE] {
+    // A non zero offset asks to continue a partial download of the file with that mtime
+    pub fn download(file_name: &str, codec: u8, offset: u64, mtime: u64) -> [u8; PACKET_SIZE] {
         let mut packet = Code::Download.packet();
+        packet[1] = codec;
+        LittleEndian::write_u64(&mut packet[2..10], offset);
+        LittleEndian::write_u64(&mut packet[10..18], mtime);
 
-        let mut offset = 1;
-        for c in file_name.as_bytes().iter() {
-            packet[offset] = *c;
-            offset += 1;
+        for (i, c) in file_name.as_bytes().iter().enumerate() {
+            packet[i + 18] = *c;
         }
 
         packet
@@ -104,19 +180,167 @@ pub mod create {
         packet[1] = port as u8;
         packet[2] = (port >> 8) as u8;
 
-        let mut offset = 3;
-        for c in filename.as_bytes().iter() {
-            packet[offset] = *c;
-            offset += 1;
+        for (i, c) in filename.as_bytes().iter().enumerate() {
+            packet[i + 3] = *c;
         }
 
         packet
     }
+
+    // A rate of 0 lifts the limit
+    pub fn limit(rate: Option<u64>) -> [u8; PACKET_SIZE] {
+        let mut packet = Code::Limit.packet();
+        LittleEndian::write_u64(&mut packet[1..9], rate.unwrap_or(0));
+
+        packet
+    }
+
+    pub fn hello(codecs: u8) -> [u8; PACKET_SIZE] {
+        let mut packet = Code::Hello.packet();
+        packet[1] = codecs;
+
+        packet
+    }
+
+    pub fn delta_upload(file_name: &str) -> io::Result<[u8; PACKET_SIZE]> {
+        let mut packet = Code::DeltaUpload.packet();
+        write_name(&mut packet[1..], file_name)?;
+
+        Ok(packet)
+    }
+
+    pub fn delta_download(file_name: &str, codec: u8) -> [u8; PACKET_SIZE] {
+        let mut packet = download(file_name, codec, 0, 0);
+        packet[0] = Code::DeltaDownload as u8;
+
+        packet
+    }
+
+    // The block signatures follow the packet
+    pub fn signature(block_size: u32, basis_len: u64, blocks: u32) -> [u8; PACKET_SIZE] {
+        let mut packet = Code::Signature.packet();
+        LittleEndian::write_u32(&mut packet[1..5], block_size);
+        LittleEndian::write_u64(&mut packet[5..13], basis_len);
+        LittleEndian::write_u32(&mut packet[13..17], blocks);
+
+        packet
+    }
+
+    // Copy len bytes from basis_offset of the receiver's old file
+    // Carries the source's mtime too, a file that didn't change is sent as nothing but copies
+    pub fn copy(basis_offset: u64, len: u64, offset: u64, total: u64, mtime: u64) -> [u8; PACKET_SIZE] {
+        let mut packet = Code::Copy.packet();
+        LittleEndian::write_u64(&mut packet[1..9], basis_offset);
+        LittleEndian::write_u64(&mut packet[9..17], len);
+        LittleEndian::write_u64(&mut packet[17..25], offset);
+        LittleEndian::write_u64(&mut packet[25..33], total);
+        LittleEndian::write_u64(&mut packet[33..41], mtime);
+
+        packet
+    }
+
+    pub fn data(chunk: &Chunk) -> [u8; PACKET_SIZE] {
+        let mut packet = Code::Data.packet();
+        LittleEndian::write_u16(&mut packet[1..3], chunk.id);
+        LittleEndian::write_u64(&mut packet[3..11], chunk.offset);
+        LittleEndian::write_u64(&mut packet[11..19], chunk.total);
+        packet[19] = chunk.codec;
+        LittleEndian::write_u32(&mut packet[20..24], chunk.raw_len);
+        LittleEndian::write_u32(&mut packet[24..28], chunk.wire_len);
+        LittleEndian::write_u64(&mut packet[28..36], chunk.mtime);
+
+        packet
+    }
+
+    pub fn list_request(path: &str, flags: u8) -> io::Result<[u8; PACKET_SIZE]> {
+        let mut packet = Code::List.packet();
+        packet[1] = flags;
+        write_name(&mut packet[2..], path)?;
+
+        Ok(packet)
+    }
+
+    // The encoded entries follow the packet
+    pub fn list(entries: u32, len: u64) -> [u8; PACKET_SIZE] {
+        let mut packet = Code::List.packet();
+        LittleEndian::write_u32(&mut packet[1..5], entries);
+        LittleEndian::write_u64(&mut packet[5..13], len);
+
+        packet
+    }
+
+    pub fn watch(path: &str) -> io::Result<[u8; PACKET_SIZE]> {
+        let mut packet = Code::Watch.packet();
+        write_name(&mut packet[1..], path)?;
+
+        Ok(packet)
+    }
+
+    // Has a byte less room for the names than a rename
+    pub fn event(event: &Event) -> io::Result<[u8; PACKET_SIZE]> {
+        let mut packet = Code::Event.packet();
+        packet[1] = event.kind();
+        write_pair(&mut packet[2..], event.path(), event.to())?;
+
+        Ok(packet)
+    }
+
+    pub fn rename(from: &str, to: &str) -> io::Result<[u8; PACKET_SIZE]> {
+        let mut packet = Code::Rename.packet();
+        write_pair(&mut packet[1..], from, to)?;
+
+        Ok(packet)
+    }
+
+    pub fn error(message: &str) -> [u8; PACKET_SIZE] {
+        let mut packet = Code::Error.packet();
+
+        for (i, c) in message.as_bytes().iter().take(PACKET_SIZE - 1).enumerate() {
+            packet[i + 1] = *c;
+        }
+
+        packet
+    }
+
+    // Asks how much of an interrupted upload the server kept
+    pub fn resume_request(file_name: &str) -> io::Result<[u8; PACKET_SIZE]> {
+        let mut packet = Code::Resume.packet();
+        write_name(&mut packet[1..], file_name)?;
+
+        Ok(packet)
+    }
+
+    // Length and mtime of the kept partial file, zeros if there is none
+    pub fn resume(len: u64, mtime: u64) -> [u8; PACKET_SIZE] {
+        let mut packet = Code::Resume.packet();
+        LittleEndian::write_u64(&mut packet[1..9], len);
+        LittleEndian::write_u64(&mut packet[9..17], mtime);
+
+        packet
+    }
+
+    // The server statistics as JSON follow the packet
+    pub fn stats(len: u64) -> [u8; PACKET_SIZE] {
+        let mut packet = Code::Stats.packet();
+        LittleEndian::write_u64(&mut packet[1..9], len);
+
+        packet
+    }
 }
 
 pub mod parse {
     use byteorder::{ByteOrder, LittleEndian};
-    use crate::net::{Code, PACKET_SIZE};
+    use crate::net::{Chunk, Code, PACKET_SIZE};
+    use crate::events::Event;
+
+    fn read_pair(packet: &[u8]) -> (String, String) {
+        let first_len = LittleEndian::read_u16(&packet[..2]) as usize;
+        let names = &packet[2..];
+        let first = String::from_utf8_lossy(&names[..first_len.min(names.len())]).into_owned();
+        let second = String::from_utf8_lossy(names.get(first_len..).unwrap_or(&[])).trim_matches(char::from(0)).to_string();
+
+        (first, second)
+    }
 
     pub fn packet(packet: &[u8; PACKET_SIZE]) -> Code {
         if packet.is_empty() {
@@ -145,16 +369,18 @@ pub mod parse {
         (name, id)
     }
 
-    pub fn download(packet: &[u8; PACKET_SIZE]) -> String {
-        String::from(String::from_utf8_lossy(&packet[1..]).into_owned().trim().trim_matches(char::from(0)))
+    pub fn download(packet: &[u8; PACKET_SIZE]) -> (String, u8, u64, u64) {
+        let name = String::from(String::from_utf8_lossy(&packet[18..]).into_owned().trim().trim_matches(char::from(0)));
+
+        (name, packet[1], LittleEndian::read_u64(&packet[2..10]), LittleEndian::read_u64(&packet[10..18]))
     }
 
     pub fn delete(packet: [u8; PACKET_SIZE]) -> String {
         String::from(String::from_utf8_lossy(&packet[1..]).into_owned().trim().trim_matches(char::from(0)))
     }
 
-    pub fn dir(_packet: [u8; PACKET_SIZE]) -> String {
-        String::new()
+    pub fn dir(packet: [u8; PACKET_SIZE]) -> String {
+        String::from(String::from_utf8_lossy(&packet[1..]).into_owned().trim().trim_matches(char::from(0)))
     }
 
     pub fn redirect(packet: [u8; PACKET_SIZE]) -> (u16, String) {
@@ -166,58 +392,186 @@ pub mod parse {
         ((b2 << 8) | b1, file)
     }
 
-    pub fn data(packet: [u8; PACKET_SIZE]) -> (u16, u64, usize) {
-        let b1 = packet[1] as u16;
-        let b2 = packet[2] as u16;
-        let id = (b2 << 8) | b1;
+    pub fn data(packet: &[u8; PACKET_SIZE]) -> Chunk {
+        Chunk {
+            id: LittleEndian::read_u16(&packet[1..3]),
+            offset: LittleEndian::read_u64(&packet[3..11]),
+            total: LittleEndian::read_u64(&packet[11..19]),
+            codec: packet[19],
+            raw_len: LittleEndian::read_u32(&packet[20..24]),
+            wire_len: LittleEndian::read_u32(&packet[24..28]),
+            mtime: LittleEndian::read_u64(&packet[28..36])
+        }
+    }
 
-        let transmitted = &packet[3..11];
-        let total = &packet[11..20];
+    pub fn list_request(packet: &[u8; PACKET_SIZE]) -> (String, u8) {
+        let path = String::from(String::from_utf8_lossy(&packet[2..]).into_owned().trim().trim_matches(char::from(0)));
 
-        let transmitted_u = LittleEndian::read_u64(transmitted);
-        let total_u = LittleEndian::read_u64(total);
+        (path, packet[1])
+    }
+
+    pub fn list(packet: &[u8; PACKET_SIZE]) -> (u32, u64) {
+        (LittleEndian::read_u32(&packet[1..5]), LittleEndian::read_u64(&packet[5..13]))
+    }
 
-        (id, transmitted_u, total_u as usize)
+    pub fn hello(packet: [u8; PACKET_SIZE]) -> u8 {
+        packet[1]
+    }
+
+    pub fn delta_upload(packet: &[u8; PACKET_SIZE]) -> String {
+        String::from(String::from_utf8_lossy(&packet[1..]).into_owned().trim().trim_matches(char::from(0)))
+    }
+
+    pub fn signature(packet: &[u8; PACKET_SIZE]) -> (u32, u64, u32) {
+        (LittleEndian::read_u32(&packet[1..5]), LittleEndian::read_u64(&packet[5..13]), LittleEndian::read_u32(&packet[13..17]))
+    }
+
+    pub fn copy(packet: &[u8; PACKET_SIZE]) -> (u64, u64, u64, u64, u64) {
+        (LittleEndian::read_u64(&packet[1..9]),
+         LittleEndian::read_u64(&packet[9..17]),
+         LittleEndian::read_u64(&packet[17..25]),
+         LittleEndian::read_u64(&packet[25..33]),
+         LittleEndian::read_u64(&packet[33..41]))
+    }
+
+    pub fn watch(packet: &[u8; PACKET_SIZE]) -> String {
+        String::from(String::from_utf8_lossy(&packet[1..]).into_owned().trim().trim_matches(char::from(0)))
+    }
+
+    pub fn event(packet: &[u8; PACKET_SIZE]) -> Option<Event> {
+        let (path, to) = read_pair(&packet[2..]);
+        Event::from_parts(packet[1], path, to)
+    }
+
+    pub fn rename(packet: &[u8; PACKET_SIZE]) -> (String, String) {
+        read_pair(&packet[1..])
+    }
+
+    pub fn error(packet: &[u8; PACKET_SIZE]) -> String {
+        String::from(String::from_utf8_lossy(&packet[1..]).into_owned().trim().trim_matches(char::from(0)))
+    }
+
+    pub fn resume_request(packet: &[u8; PACKET_SIZE]) -> String {
+        String::from(String::from_utf8_lossy(&packet[1..]).into_owned().trim().trim_matches(char::from(0)))
+    }
+
+    pub fn resume(packet: &[u8; PACKET_SIZE]) -> (u64, u64) {
+        (LittleEndian::read_u64(&packet[1..9]), LittleEndian::read_u64(&packet[9..17]))
+    }
+
+    pub fn stats(packet: &[u8; PACKET_SIZE]) -> u64 {
+        LittleEndian::read_u64(&packet[1..9])
+    }
+
+    pub fn limit(packet: [u8; PACKET_SIZE]) -> Option<u64> {
+        match LittleEndian::read_u64(&packet[1..9]) {
+            0 => None,
+            rate => Some(rate)
+        }
     }
 }
 
-use byteorder::{LittleEndian, WriteBytesExt};
+// Whether the peer has sent something or hung up, without waiting for it
+pub fn readable(stream: &TcpStream) -> bool {
+    let mut buf = [0; 1];
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let result = stream.peek(&mut buf);
+    stream.set_nonblocking(false).ok();
 
-pub fn mod_data(packet: &mut [u8; PACKET_SIZE], object: u16, bytes_t: u64, bytes_s: u64) {
-    packet[0] = Code::Data as u8;
+    !matches!(result, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock)
+}
 
-    packet[1] = object as u8;
-    packet[2] = (object >> 8) as u8;
+// A server to connect to, kept as given so it is looked up again on every connect
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16
+}
 
-    let mut b1 = [0u8; mem::size_of::<u64>()];
-    b1.as_mut()
-        .write_u64::<LittleEndian>(bytes_t)
-        .expect("Unable to write to packet");
+impl Target {
+    // Accepts host, host:port, [v6]:port and bare v6 addresses, port is used when none is given
+    pub fn parse(target: &str, port: Option<u16>) -> io::Result<Target> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let parse_port = |p: &str| p.parse::<u16>().map_err(|_| invalid(format!("Invalid port {}", p)));
+
+        let (host, given) = if let Some(rest) = target.strip_prefix('[') {
+            let end = rest.find(']').ok_or_else(|| invalid(format!("Missing ] in {}", target)))?;
+            match &rest[end + 1..] {
+                "" => (&rest[..end], None),
+                after => match after.strip_prefix(':') {
+                    Some(p) => (&rest[..end], Some(parse_port(p)?)),
+                    None => return Err(invalid(format!("Unexpected {} after the address", after)))
+                }
+            }
+        }
+        // More than one colon can only be an IPv6 address on its own
+        else if target.matches(':').count() == 1 {
+            let (host, p) = target.split_at(target.find(':').unwrap());
+            (host, Some(parse_port(&p[1..])?))
+        }
+        else {
+            (target, None)
+        };
 
-    packet[3..(8 + 3)].clone_from_slice(&b1[..8]);
+        if host.is_empty() {
+            return Err(invalid(String::from("Missing host")));
+        }
+        match (given, port) {
+            (Some(given), Some(port)) if given != port => Err(invalid(format!("Conflicting ports {} and {}", given, port))),
+            (given, port) => Ok(Target { host: String::from(host), port: given.or(port).unwrap_or(DEFAULT_PORT) })
+        }
+    }
 
-    let mut b2 = [0u8; mem::size_of::<u64>()];
-    b2.as_mut()
-        .write_u64::<LittleEndian>(bytes_s)
-        .expect("Unable to write to packet");
+    // Every address the host resolves to, DNS is asked each time
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = (self.host.as_str(), self.port).to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", self.host)));
+        }
+        Ok(addrs)
+    }
 
-    packet[11..(8+11)].clone_from_slice(&b2[..8]);
+    // Tries each address in turn, the last failure is reported if none answer
+    pub fn connect(&self, timeout: Option<Duration>) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.resolve()? {
+            let result = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr)
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e)
+            }
+        }
+        Err(last_error.unwrap())
+    }
 }
 
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        }
+        else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
 
 pub struct Connection {
     pub name: String,
+    pub target: Option<Target>,
     pub stream: Option<TcpStream>
 }
 
 impl Connection {
-    pub fn new(ip: net::IpAddr, port: u16) -> Connection {
-        Connection{name: String::from("Default name"), stream: Some(TcpStream::connect((ip, port)).unwrap())}
-    }
-    pub fn default() -> Connection {
-        Connection{ name: String::from("no connection"), stream: None }
+    pub fn new(target: Target) -> io::Result<Connection> {
+        let stream = target.connect(None)?;
+        Ok(Connection{name: target.to_string(), target: Some(target), stream: Some(stream)})
     }
-
     pub fn connected(&self) -> bool {
         match &self.stream {
             Some(_stream) => true,
@@ -225,3 +579,9 @@ impl Connection {
         }
     }
 }
+
+impl Default for Connection {
+    fn default() -> Connection {
+        Connection{ name: String::from("no connection"), target: None, stream: None }
+    }
+}
@@ -0,0 +1,337 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::RngCore;
+use serde::Serialize;
+use serde_json::json;
+use crate::audit::AuditLog;
+use crate::compression::Codec;
+use crate::encoding::{format_bytes, format_duration};
+use crate::metrics::Metrics;
+use crate::net::{self, Target};
+use crate::net::client::commands;
+use crate::net::client::session::Session;
+use crate::net::server::{ConnectionListener, Limits};
+use crate::retry::RetryPolicy;
+use crate::stats::ServerStats;
+use crate::output;
+
+// Remote files go here, so a bench against a real server cleans up after itself
+const REMOTE_DIR: &str = "netfolder-bench";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Upload,
+    Download
+}
+
+impl Direction {
+    fn name(&self) -> &'static str {
+        match self {
+            Direction::Upload => "upload",
+            Direction::Download => "download"
+        }
+    }
+}
+
+// What was asked for, shared by every worker
+struct Plan {
+    target: Target,
+    codec: Codec,
+    size: u64,
+    count: usize,
+    concurrency: usize,
+    payload: PathBuf,
+    work_dir: PathBuf
+}
+
+impl Plan {
+    fn remote(&self, worker: usize, i: usize) -> String {
+        format!("{}/{}-{}-{}.bin", REMOTE_DIR, std::process::id(), worker, i)
+    }
+}
+
+// One direction of a run, rates in bytes per second and times in milliseconds
+#[derive(Debug, Serialize)]
+struct Report {
+    version: &'static str,
+    direction: &'static str,
+    codec: &'static str,
+    size: u64,
+    transfers: usize,
+    concurrency: usize,
+    bytes: u64,
+    wire_bytes: u64,
+    seconds: f64,
+    throughput: f64,
+    latency_min_ms: f64,
+    latency_avg_ms: f64,
+    latency_p50_ms: f64,
+    latency_p95_ms: f64,
+    latency_max_ms: f64,
+    // Of one core, so several busy threads go over 100
+    cpu_percent: Option<f64>
+}
+
+impl Report {
+    const CSV_HEADER: &'static str = "version,direction,codec,size,transfers,concurrency,bytes,wire_bytes,seconds,throughput,latency_min_ms,latency_avg_ms,latency_p50_ms,latency_p95_ms,latency_max_ms,cpu_percent";
+
+    fn csv_row(&self) -> String {
+        format!("{},{},{},{},{},{},{},{},{:.3},{:.0},{:.3},{:.3},{:.3},{:.3},{:.3},{}",
+            self.version, self.direction, self.codec, self.size, self.transfers, self.concurrency, self.bytes, self.wire_bytes,
+            self.seconds, self.throughput, self.latency_min_ms, self.latency_avg_ms, self.latency_p50_ms, self.latency_p95_ms,
+            self.latency_max_ms, self.cpu_percent.map(|cpu| format!("{:.1}", cpu)).unwrap_or_default())
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}: {} x {} over {} connections, {} compression", self.direction, self.transfers, format_bytes(self.size as usize),
+            self.concurrency, self.codec)?;
+        writeln!(f, "  Throughput: {}/s, {} in {}, {} on the wire", format_bytes(self.throughput as usize), format_bytes(self.bytes as usize),
+            format_duration(Duration::from_secs_f64(self.seconds)), format_bytes(self.wire_bytes as usize))?;
+        writeln!(f, "  Latency:    {:.1} ms min, {:.1} ms average, {:.1} ms p50, {:.1} ms p95, {:.1} ms max",
+            self.latency_min_ms, self.latency_avg_ms, self.latency_p50_ms, self.latency_p95_ms, self.latency_max_ms)?;
+        match self.cpu_percent {
+            Some(cpu) => write!(f, "  CPU:        {:.0}%", cpu),
+            None => write!(f, "  CPU:        unknown")
+        }
+    }
+}
+
+// User and system time of the whole process, the built in server included
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Option<Duration> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let time = |t: libc::timeval| Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64);
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+// The value below which the given share of the sorted latencies fall
+fn percentile(sorted: &[f64], share: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((sorted.len() as f64 * share).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+// Sizes like 4096, 512K or 1.5GB, in bytes
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_ascii_uppercase();
+    let size = size.trim_end_matches('B');
+    let (number, multiplier) = match size.chars().last() {
+        Some('K') => (&size[..size.len() - 1], 1000.0),
+        Some('M') => (&size[..size.len() - 1], 1000000.0),
+        Some('G') => (&size[..size.len() - 1], 1000000000.0),
+        _ => (size, 1.0)
+    };
+
+    match number.parse::<f64>() {
+        Ok(n) if (n * multiplier) as u64 > 0 => Some((n * multiplier) as u64),
+        _ => None
+    }
+}
+
+// Random bytes, so compression has as little to work with as it would with media files
+fn write_payload(path: &Path, size: u64) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    let mut block = vec![0; net::CHUNK_SIZE];
+    let mut left = size;
+    while left > 0 {
+        let len = left.min(block.len() as u64) as usize;
+        rand::thread_rng().fill_bytes(&mut block[..len]);
+        file.write_all(&block[..len])?;
+        left -= len as u64;
+    }
+    Ok(())
+}
+
+fn open_session(plan: &Plan) -> io::Result<Session> {
+    let mut session = Session::connect(plan.target.clone(), RetryPolicy { retries: 0, ..RetryPolicy::default() })?;
+    let codec = plan.codec;
+    session.run(|transmitter, receiver, stream| commands::negotiate(transmitter, receiver, stream, codec))?;
+    Ok(session)
+}
+
+// Every worker opens its connection before the clock starts
+fn run(plan: &Arc<Plan>, direction: Direction) -> io::Result<Report> {
+    let start = Arc::new(Barrier::new(plan.concurrency + 1));
+    let workers: Vec<_> = (0..plan.concurrency).map(|worker| {
+        let plan = plan.clone();
+        let start = start.clone();
+        thread::spawn(move || -> io::Result<(Vec<Duration>, u64, u64)> {
+            let session = open_session(&plan);
+            start.wait();
+            let mut session = session?;
+
+            let mut latencies = Vec::new();
+            let (mut bytes, mut wire_bytes) = (0, 0);
+            for i in 0..plan.count {
+                let remote = plan.remote(worker, i);
+                let instant = Instant::now();
+                let stats = match direction {
+                    Direction::Upload => session.run(|transmitter, _, stream| commands::upload(transmitter, stream, &plan.payload, &remote, false))?,
+                    Direction::Download => {
+                        let local = plan.work_dir.join(format!("{}-{}.bin", worker, i));
+                        let local = local.to_string_lossy();
+                        session.run(|_, receiver, stream| commands::download(receiver, stream, &remote, &local, false))?
+                    }
+                };
+                latencies.push(instant.elapsed());
+                bytes += stats.bytes() as u64;
+                wire_bytes += stats.wire_bytes() as u64;
+            }
+            commands::disconnect(&mut session.stream, &mut session.transmitter, &mut session.receiver).ok();
+            Ok((latencies, bytes, wire_bytes))
+        })
+    }).collect();
+
+    start.wait();
+    let instant = Instant::now();
+    let cpu_before = cpu_time();
+
+    let mut latencies = Vec::new();
+    let (mut bytes, mut wire_bytes) = (0, 0);
+    for worker in workers {
+        let (worker_latencies, worker_bytes, worker_wire_bytes) = worker.join().expect("Bench worker panicked")?;
+        latencies.extend(worker_latencies.iter().map(|l| l.as_secs_f64() * 1000.0));
+        bytes += worker_bytes;
+        wire_bytes += worker_wire_bytes;
+    }
+
+    let seconds = instant.elapsed().as_secs_f64();
+    let cpu_percent = cpu_before.zip(cpu_time()).map(|(before, after)| (after - before).as_secs_f64() / seconds * 100.0);
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    Ok(Report {
+        version: env!("CARGO_PKG_VERSION"),
+        direction: direction.name(),
+        codec: plan.codec.name(),
+        size: plan.size,
+        transfers: latencies.len(),
+        concurrency: plan.concurrency,
+        bytes,
+        wire_bytes,
+        seconds,
+        throughput: if seconds > 0.0 { bytes as f64 / seconds } else { 0.0 },
+        latency_min_ms: latencies.first().copied().unwrap_or(0.0),
+        latency_avg_ms: if latencies.is_empty() { 0.0 } else { latencies.iter().sum::<f64>() / latencies.len() as f64 },
+        latency_p50_ms: percentile(&latencies, 0.5),
+        latency_p95_ms: percentile(&latencies, 0.95),
+        latency_max_ms: latencies.last().copied().unwrap_or(0.0),
+        cpu_percent
+    })
+}
+
+// Removes what the uploads left on the server, then the directory if nothing else is in it
+fn clean_up(plan: &Plan) -> io::Result<()> {
+    let mut session = open_session(plan)?;
+    for worker in 0..plan.concurrency {
+        for i in 0..plan.count {
+            let remote = plan.remote(worker, i);
+            session.replaying(|_, receiver, stream, replay| commands::delete(receiver, stream, &remote, replay)).ok();
+        }
+    }
+    session.replaying(|_, receiver, stream, replay| commands::delete(receiver, stream, REMOTE_DIR, replay)).ok();
+    commands::disconnect(&mut session.stream, &mut session.transmitter, &mut session.receiver)
+}
+
+// A server of our own on loopback, serving the directory it's given
+fn start_local_server(dir: &Path) -> io::Result<Target> {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))?;
+    let port = listener.local_addr()?.port();
+    let listener = ConnectionListener::new("bench", listener, dir.to_path_buf(), Limits::default(), AuditLog::default(), Metrics::new(ServerStats::new()));
+    thread::spawn(move || listener.connection_loop());
+    Target::parse("127.0.0.1", Some(port))
+}
+
+fn bench(matches: &clap::ArgMatches, work_dir: &Path) -> io::Result<Vec<Report>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let size = matches.value_of("size").unwrap();
+    let size = parse_size(size).ok_or_else(|| invalid(format!("Invalid size {}, expected something like 64M", size)))?;
+    let count = matches.value_of("count").unwrap().parse().map_err(|_| invalid(String::from("Invalid count")))?;
+    let concurrency: usize = matches.value_of("concurrency").unwrap().parse().map_err(|_| invalid(String::from("Invalid concurrency")))?;
+    if concurrency == 0 {
+        return Err(invalid(String::from("Concurrency has to be at least 1")));
+    }
+    let codec = Codec::from_name(matches.value_of("compress").unwrap()).unwrap();
+
+    let payload = work_dir.join("payload.bin");
+    write_payload(&payload, size)?;
+    let target = match matches.value_of("host") {
+        Some(host) => Target::parse(host, matches.value_of("port").map(|port| port.parse().expect("Please provide a valid port")))?,
+        None => {
+            let served = work_dir.join("served");
+            fs::create_dir_all(&served)?;
+            start_local_server(&served)?
+        }
+    };
+
+    let plan = Arc::new(Plan { target, codec, size, count, concurrency, payload, work_dir: work_dir.to_path_buf() });
+    let directions = match matches.value_of("direction").unwrap() {
+        "upload" => vec![Direction::Upload],
+        // Downloads fetch what the uploads put there
+        _ => vec![Direction::Upload, Direction::Download]
+    };
+    let download_only = matches.value_of("direction") == Some("download");
+
+    let mut reports = Vec::new();
+    for direction in directions {
+        let report = run(&plan, direction)?;
+        if !(download_only && direction == Direction::Upload) {
+            reports.push(report);
+        }
+    }
+    if matches.is_present("host") {
+        clean_up(&plan)?;
+    }
+    Ok(reports)
+}
+
+// Start the benchmark
+pub fn start_bench(matches: &clap::ArgMatches) {
+    // Transfers are timed, not watched, so their chatter and progress bars stay out of the way
+    output::set_mode(output::Mode::Log);
+
+    let work_dir = std::env::temp_dir().join(format!("netfolder-bench-{}", std::process::id()));
+    let result = fs::create_dir_all(&work_dir).and_then(|_| bench(matches, &work_dir));
+    fs::remove_dir_all(&work_dir).ok();
+
+    let reports = result.unwrap_or_else(|e| {
+        colour::red_ln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    match matches.value_of("output").unwrap() {
+        "json" => {
+            for report in reports.iter() {
+                let mut value = serde_json::to_value(report).unwrap_or_default();
+                value["type"] = json!("bench");
+                println!("{}", value);
+            }
+        },
+        "csv" => {
+            println!("{}", Report::CSV_HEADER);
+            for report in reports.iter() {
+                println!("{}", report.csv_row());
+            }
+        },
+        _ => {
+            for report in reports.iter() {
+                println!("{}", report);
+            }
+        }
+    }
+}
@@ -26,256 +26,1808 @@ mod error {
     }
 }
 
-mod commands {
-    use std::net::{IpAddr, TcpStream};
+pub mod commands {
+    use std::net::TcpStream;
     use std::path::Path;
-    use std::io::Write;
-    use crate::encoding::{FileTransmitter, FileReceiver};
-    use crate::net::{self, create};
+    use std::io::{self, Read, Write};
+    use std::time::Instant;
+    use crate::encoding::{self, FileTransmitter, FileReceiver};
+    use crate::net::{self, create, parse};
+    use crate::throttle::Limiter;
+    use crate::compression::Codec;
+    use crate::stats::{Summary, TransferStats};
+    use crate::{history, listing, output, retry, sync, watcher};
+    use serde_json::json;
 
     // Connection handling
-    pub fn connect(connection: &mut net::Connection, ip: IpAddr, port: u16) {
-            *connection = net::Connection::new(ip, port);
+    pub fn connect(connection: &mut net::Connection, target: net::Target) -> io::Result<()> {
+            *connection = net::Connection::new(target)?;
+            Ok(())
+    }
+
+    // Caps our side and asks the server to pace its side to match
+    pub fn limit(limiter: &Limiter, stream: &mut TcpStream, rate: Option<u64>) -> io::Result<()> {
+        limiter.set_rate(rate);
+        stream.write_all(&create::limit(rate))
     }
 
-    pub fn disconnect(stream: &mut TcpStream, _transmitter: &mut FileTransmitter, _receiver: &mut FileReceiver) {
+    pub fn disconnect(stream: &mut TcpStream, _transmitter: &mut FileTransmitter, _receiver: &mut FileReceiver) -> io::Result<()> {
         let mut packet = [0; net::PACKET_SIZE];
         packet[0] = net::Code::Disconnect as u8;
-        stream.write_all(&packet).expect("Network error");
+        stream.write_all(&packet)
+    }
+
+    // Settles on a codec both ends understand and uses it in both directions
+    pub fn negotiate(transmitter: &mut FileTransmitter, receiver: &mut FileReceiver, stream: &mut TcpStream, codec: Codec) -> io::Result<Codec> {
+        let codec = if codec == Codec::None {
+            codec
+        }
+        else {
+            stream.write_all(&create::hello(Codec::supported()))?;
+            let mut packet = [0; net::PACKET_SIZE];
+            stream.read_exact(&mut packet)?;
+            codec.negotiate(parse::hello(packet))
+        };
+
+        transmitter.set_codec(codec);
+        receiver.set_codec(codec);
+        Ok(codec)
+    }
+
+    // How much of an interrupted upload of path the server kept, if it is still the same file
+    fn resume_offset(stream: &mut TcpStream, path: &Path, remote: &str) -> io::Result<u64> {
+        stream.write_all(&create::resume_request(remote)?)?;
+        let mut packet = [0; net::PACKET_SIZE];
+        stream.read_exact(&mut packet)?;
+        if parse::packet(&packet) != net::Code::Resume {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected a resume offset, got {:?}", parse::packet(&packet))));
+        }
+
+        let (len, mtime) = parse::resume(&packet);
+        let metadata = std::fs::metadata(path)?;
+        let current = metadata.modified().map(listing::to_nanos).unwrap_or(0);
+        Ok(if len <= metadata.len() && mtime == current { len } else { 0 })
+    }
+
+    // The server answers every upload once the file is in place
+    fn read_ack(stream: &mut TcpStream) -> io::Result<()> {
+        let mut packet = [0; net::PACKET_SIZE];
+        stream.read_exact(&mut packet)?;
+        match parse::packet(&packet) {
+            net::Code::Okay => Ok(()),
+            net::Code::Error => Err(io::Error::other(format!("Server refused the upload: {}", parse::error(&packet)))),
+            code => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected an acknowledgement, got {:?}", code)))
+        }
+    }
+
+    // The server only says something when a request didn't go through
+    fn read_outcome(stream: &mut TcpStream) -> io::Result<()> {
+        let mut packet = [0; net::PACKET_SIZE];
+        let mut message = String::new();
+        loop {
+            stream.read_exact(&mut packet)?;
+            match parse::packet(&packet) {
+                net::Code::Stdout => message.push_str(String::from_utf8_lossy(&packet[1..]).trim_end_matches('\0')),
+                net::Code::End if message.is_empty() => return Ok(()),
+                net::Code::End => return Err(io::Error::other(message)),
+                code => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected {:?} packet", code)))
+            }
+        }
     }
 
     // User commands
-    pub fn upload(transmitter: &mut FileTransmitter, stream: &mut TcpStream, path: &Path) {
-        let upload_packet = create::upload(path.file_name().unwrap().to_str().unwrap(), 0x1);
-        stream.write_all(&upload_packet).expect("Unable to write to stream");
-        let stats = transmitter.host_file(path.to_str().unwrap(), stream);
-        println!("{}", stats);
+    pub fn upload(transmitter: &mut FileTransmitter, stream: &mut TcpStream, path: &Path, remote: &str, delta: bool) -> io::Result<TransferStats> {
+        let name = remote;
+        let stats = if delta {
+            stream.write_all(&create::delta_upload(name)?)?;
+            let signature = transmitter.read_signature(stream)?;
+            transmitter.host_delta(path.to_str().unwrap(), &signature, stream)?
+        }
+        else {
+            // Asking costs a round trip, small files are quicker to send again
+            let offset = if std::fs::metadata(path)?.len() > net::CHUNK_SIZE as u64 { resume_offset(stream, path, name)? } else { 0 };
+            stream.write_all(&create::upload(name, 0x1))?;
+            transmitter.host_file_from(path.to_str().unwrap(), offset, stream)?
+        };
+        read_ack(stream)?;
+        Ok(stats)
+    }
+
+    pub fn download(receiver: &mut FileReceiver, stream: &mut TcpStream, path: &str, local: &str, delta: bool) -> io::Result<TransferStats> {
+        if delta {
+            stream.write_all(&create::delta_download(path, receiver.codec() as u8))?;
+            receiver.send_signature(local, stream)?;
+        }
+        else {
+            // Picks up after an earlier attempt that was cut off, the server checks it's the same file
+            let part = encoding::part_path(Path::new(local));
+            let offset = encoding::partial_len(&part);
+            let mtime = if offset > 0 { std::fs::metadata(&part)?.modified().map(listing::to_nanos).unwrap_or(0) } else { 0 };
+            stream.write_all(&create::download(path, receiver.codec() as u8, offset, mtime))?;
+        }
+        receiver.listen(stream, Some(local))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "The server didn't send the file"))
+    }
+
+    // Transfers reported as they finish, for commands that make several
+    pub fn upload_and_report(transmitter: &mut FileTransmitter, stream: &mut TcpStream, path: &Path, remote: &str, delta: bool) -> io::Result<()> {
+        let failed = TransferStats::new();
+        let result = upload(transmitter, stream, path, remote, delta);
+        report("upload", &path.to_string_lossy(), remote, result, failed)
+    }
+
+    pub fn download_and_report(receiver: &mut FileReceiver, stream: &mut TcpStream, path: &str, local: &str, delta: bool) -> io::Result<()> {
+        let failed = TransferStats::new();
+        let result = download(receiver, stream, path, local, delta);
+        report("download", local, path, result, failed)
+    }
+
+    // Failures are only logged, the caller decides what to tell the user
+    fn report(kind: &str, local: &str, remote: &str, result: io::Result<TransferStats>, mut failed: TransferStats) -> io::Result<()> {
+        match result {
+            Ok(stats) => {
+                output::transfer(kind, local, remote, &stats);
+                Ok(())
+            },
+            Err(e) => {
+                failed.fail(&e);
+                output::log_transfer(kind, local, remote, &failed);
+                Err(e)
+            }
+        }
+    }
+
+    // A replay is the same request again on a new connection, the first may have gone through before it dropped
+    pub fn delete(receiver: &mut FileReceiver, stream: &mut TcpStream, path: &str, replay: bool) -> io::Result<()> {
+        let instant = Instant::now();
+        let mut result = stream.write_all(&create::delete(path)).and_then(|_| read_outcome(stream));
+        if replay && result.as_ref().is_err_and(|e| !retry::is_retryable(e)) && !exists(receiver, stream, path)? {
+            result = Ok(());
+        }
+        history::operation("delete", path, None, instant.elapsed(), &result);
+        result?;
+        output::record(json!({"type": "delete", "path": path}));
+        Ok(())
+    }
+
+    // The server's own listing as text, or the entries as JSON
+    pub fn dir(receiver: &mut FileReceiver, stream: &mut TcpStream, path: &str) -> io::Result<()> {
+        if output::json() {
+            let entries = list_shallow(receiver, stream, path)?;
+            output::record(json!({"type": "listing", "path": path, "entries": entries}));
+            return Ok(());
+        }
+        let dir_packet = create::dir(path);
+        stream.write_all(&dir_packet)?;
+        receiver.listen(stream, None)?;
+        Ok(())
+    }
+
+    pub fn rename(receiver: &mut FileReceiver, stream: &mut TcpStream, from: &str, to: &str, replay: bool) -> io::Result<()> {
+        let instant = Instant::now();
+        let mut result = create::rename(from, to).and_then(|packet| stream.write_all(&packet)).and_then(|_| read_outcome(stream));
+        if replay && result.as_ref().is_err_and(|e| !retry::is_retryable(e)) && !exists(receiver, stream, from)? && exists(receiver, stream, to)? {
+            result = Ok(());
+        }
+        history::operation("rename", from, Some(to), instant.elapsed(), &result);
+        result?;
+        output::record(json!({"type": "rename", "from": from, "to": to}));
+        Ok(())
+    }
+
+    // Prints changes under path as the server reports them, until it goes away
+    pub fn watch(stream: &mut TcpStream, path: &str) -> io::Result<()> {
+        // Quiet periods are expected here
+        stream.set_read_timeout(None)?;
+        stream.write_all(&create::watch(path)?)?;
+        note!("Watching {} for changes", if path.is_empty() { "the server" } else { path });
+
+        let mut packet = [0; net::PACKET_SIZE];
+        while stream.read_exact(&mut packet).is_ok() {
+            match parse::packet(&packet) {
+                net::Code::Event => {
+                    if let Some(event) = parse::event(&packet) {
+                        let now = chrono::Local::now();
+                        output::result(format!("{} {}", now.format("%H:%M:%S"), event), json!({
+                            "type": "event",
+                            "time": now.to_rfc3339(),
+                            "event": event.name(),
+                            "path": event.path(),
+                            "to": if event.to().is_empty() { None } else { Some(event.to()) }
+                        }));
+                    }
+                },
+                net::Code::Stdout => note!("{}", String::from_utf8_lossy(&packet[1..]).trim_matches(char::from(0))),
+                net::Code::End => break,
+                _ => {}
+            }
+        }
+        note!("");
+        note!("Server closed the connection");
+        Ok(())
+    }
+
+    // Only administrators get an answer, everyone else is told why not
+    pub fn stats(stream: &mut TcpStream) -> io::Result<Summary> {
+        stream.write_all(&net::Code::Stats.packet())?;
+        let mut packet = [0; net::PACKET_SIZE];
+        stream.read_exact(&mut packet)?;
+        if parse::packet(&packet) == net::Code::Stdout {
+            let message = String::from_utf8_lossy(&packet[1..]).trim_end_matches('\0').to_string();
+            read_outcome(stream).ok();
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+        }
+        if parse::packet(&packet) != net::Code::Stats {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected statistics, got {:?}", parse::packet(&packet))));
+        }
+
+        let mut bytes = Vec::new();
+        stream.take(parse::stats(&packet)).read_to_end(&mut bytes)?;
+        serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // The entries directly inside path, named relative to it
+    pub fn list_shallow(receiver: &mut FileReceiver, stream: &mut TcpStream, path: &str) -> io::Result<Vec<listing::Entry>> {
+        stream.write_all(&create::list_request(path, net::LIST_SHALLOW)?)?;
+        receiver.read_listing(stream)
+    }
+
+    // A path's directory on the server and its name in there
+    fn split(path: &str) -> (&str, &str) {
+        match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", path)
+        }
+    }
+
+    // Whether path is a directory on the server, the served directory always is
+    pub fn is_dir(receiver: &mut FileReceiver, stream: &mut TcpStream, path: &str) -> io::Result<bool> {
+        let (parent, name) = split(path);
+        if name.is_empty() {
+            return Ok(true);
+        }
+
+        let entries = list_shallow(receiver, stream, parent)?;
+        Ok(entries.iter().any(|e| e.is_dir && e.path == name))
+    }
+
+    // Whether there is a file or directory at path on the server
+    pub fn exists(receiver: &mut FileReceiver, stream: &mut TcpStream, path: &str) -> io::Result<bool> {
+        let (parent, name) = split(path);
+        if name.is_empty() {
+            return Ok(true);
+        }
+
+        let entries = list_shallow(receiver, stream, parent)?;
+        Ok(entries.iter().any(|e| e.path == name))
+    }
+
+    // Recursive listing of a remote directory with sizes and times
+    pub fn list(receiver: &mut FileReceiver, stream: &mut TcpStream, path: &str, hash: bool) -> io::Result<Vec<listing::Entry>> {
+        let flags = if hash { net::LIST_HASH } else { 0 };
+        stream.write_all(&create::list_request(path, flags)?)?;
+        receiver.read_listing(stream)
     }
 
-    pub fn download(receiver: &mut FileReceiver, stream: &mut TcpStream, path: &str) {
-        let download_packet = create::download(path);
-        stream.write_all(&download_packet).expect("Unable to write to stream");
-        receiver.listen(stream);
+    pub struct SyncOptions {
+        pub direction: sync::Direction,
+        pub compare: sync::Compare,
+        pub delete: bool,
+        pub dry_run: bool,
+        pub delta: bool
     }
 
-    pub fn delete(receiver: &mut FileReceiver, stream: &mut TcpStream, path: &str) {
-            let delete_packet = create::delete(path);
-            stream.write_all(&delete_packet).expect("Network error");
-            receiver.listen(stream);
+    // Makes one side a mirror of the other, returns the actions taken
+    pub fn sync(transmitter: &mut FileTransmitter, receiver: &mut FileReceiver, stream: &mut TcpStream, local: &Path, remote: &str, options: &SyncOptions) -> io::Result<Vec<sync::Action>> {
+        let hash = options.compare == sync::Compare::Checksum;
+        let local_entries = listing::scan(local, hash)?;
+        let remote_entries = list(receiver, stream, remote, hash)?;
+
+        let actions = match options.direction {
+            sync::Direction::Push => sync::plan(&local_entries, &remote_entries, options.compare, options.delete),
+            sync::Direction::Pull => sync::plan(&remote_entries, &local_entries, options.compare, options.delete)
+        };
+
+        for action in actions.iter() {
+            output::action(action);
+            if options.dry_run {
+                continue;
+            }
+
+            match (options.direction, action) {
+                (sync::Direction::Push, sync::Action::Create(path)) | (sync::Direction::Push, sync::Action::Update(path)) => {
+                    let delta = options.delta && matches!(action, sync::Action::Update(_));
+                    upload_and_report(transmitter, stream, &local.join(path), &sync::remote_path(remote, path), delta)?;
+                },
+                (sync::Direction::Push, sync::Action::Delete(path)) | (sync::Direction::Push, sync::Action::DeleteDir(path)) => {
+                    delete(receiver, stream, &sync::remote_path(remote, path), false)?;
+                },
+                (sync::Direction::Pull, sync::Action::Create(path)) | (sync::Direction::Pull, sync::Action::Update(path)) => {
+                    let delta = options.delta && matches!(action, sync::Action::Update(_));
+                    let dest = local.join(path);
+                    download_and_report(receiver, stream, &sync::remote_path(remote, path), dest.to_str().unwrap(), delta)?;
+                },
+                (sync::Direction::Pull, sync::Action::Delete(path)) => {
+                    std::fs::remove_file(local.join(path))?;
+                },
+                (sync::Direction::Pull, sync::Action::DeleteDir(path)) => {
+                    std::fs::remove_dir(local.join(path))?;
+                }
+            }
+        }
+
+        if actions.is_empty() {
+            note!("Already in sync");
+        }
+        Ok(actions)
     }
 
-    pub fn dir(receiver: &mut FileReceiver, stream: &mut TcpStream) {
-        let dir_packet = create::dir("");
-        stream.write_all(&dir_packet).expect("Network error");
-        receiver.listen(stream);
+    // Uploads whatever under local is new or changed, nothing is deleted and temporary files are left out
+    pub fn push(transmitter: &mut FileTransmitter, receiver: &mut FileReceiver, stream: &mut TcpStream, local: &Path, remote: &str, delta: bool) -> io::Result<Vec<sync::Action>> {
+        let local_entries: Vec<listing::Entry> = listing::scan(local, false)?.into_iter()
+            .filter(|e| !e.path.split('/').any(watcher::is_temporary))
+            .collect();
+        let remote_entries = list(receiver, stream, remote, false)?;
+        let actions = sync::plan(&local_entries, &remote_entries, sync::Compare::SizeAndTime, false);
+
+        for action in actions.iter() {
+            if let sync::Action::Create(path) | sync::Action::Update(path) = action {
+                output::action(action);
+                let delta = delta && matches!(action, sync::Action::Update(_));
+                upload_and_report(transmitter, stream, &local.join(path), &sync::remote_path(remote, path), delta)?;
+            }
+        }
+
+        if actions.is_empty() {
+            note!("Already in sync");
+        }
+        Ok(actions)
+    }
+
+    // Brings both sides up to date with each other's edits since the last sync
+    pub fn sync_two_way(transmitter: &mut FileTransmitter, receiver: &mut FileReceiver, stream: &mut TcpStream, local: &Path, remote: &str, dry_run: bool) -> io::Result<Vec<sync::TwoWayAction>> {
+        let mut state = sync::SyncState::load(local, remote)?;
+        let local_entries = listing::scan(local, false)?;
+        let remote_entries = list(receiver, stream, remote, false)?;
+        let actions = sync::plan_two_way(&local_entries, &remote_entries, &state);
+
+        let host = hostname::get().map(|h| h.to_string_lossy().into_owned()).unwrap_or_else(|_| String::from("unknown"));
+        let date = chrono::Local::now().format("%Y-%m-%d").to_string();
+
+        for action in actions.iter() {
+            output::action(action);
+            if dry_run {
+                continue;
+            }
+
+            match action {
+                sync::TwoWayAction::Upload(path) => {
+                    upload_and_report(transmitter, stream, &local.join(path), &sync::remote_path(remote, path), false)?;
+                },
+                sync::TwoWayAction::Download(path) => {
+                    let dest = local.join(path);
+                    download_and_report(receiver, stream, &sync::remote_path(remote, path), dest.to_str().unwrap(), false)?;
+                },
+                sync::TwoWayAction::DeleteLocal(path) => {
+                    std::fs::remove_file(local.join(path))?;
+                },
+                sync::TwoWayAction::DeleteRemote(path) => {
+                    delete(receiver, stream, &sync::remote_path(remote, path), false)?;
+                },
+                sync::TwoWayAction::Conflict(path) => {
+                    // The server copy keeps the name, ours is set aside on both sides
+                    let conflict = sync::conflict_path(path, &host, &date, |p| {
+                        local.join(p).exists() || remote_entries.iter().any(|e| e.path == p)
+                    });
+                    std::fs::rename(local.join(path), local.join(&conflict))?;
+
+                    let dest = local.join(path);
+                    download_and_report(receiver, stream, &sync::remote_path(remote, path), dest.to_str().unwrap(), false)?;
+                    upload_and_report(transmitter, stream, &local.join(&conflict), &sync::remote_path(remote, &conflict), false)?;
+                    output::result(format!("Kept local version as {}", conflict), json!({"type": "conflict", "path": path, "kept_as": conflict}));
+                }
+            }
+        }
+
+        if actions.is_empty() {
+            note!("Already in sync");
+        }
+
+        if !dry_run {
+            let local_entries = listing::scan(local, false)?;
+            let remote_entries = list(receiver, stream, remote, false)?;
+            state.record(&local_entries, &remote_entries);
+            state.save(local)?;
+        }
+        Ok(actions)
     }
 }
 
-mod shell {
-    use std::path::Path;
-    use std::error::Error;
+pub mod session {
+    use std::io;
     use std::net::TcpStream;
-    use std::io::{self, Write};
+    use std::thread;
+    use std::time::Duration;
     use crate::encoding::{FileTransmitter, FileReceiver};
-    use crate::net::client::{error, commands};
-    use crate::net;
+    use crate::net::Target;
+    use crate::net::client::commands;
+    use crate::output;
+    use crate::retry::{self, Backoff, RetryPolicy};
+    use crate::stats::TransferStats;
+    use crate::throttle::Limiter;
 
-    //Commands
-    fn upload(transmitter: &mut FileTransmitter, args: Vec<&str>, stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
-        if args.len() == 1 {
-            let path = Path::new(args[0]);
-            commands::upload(transmitter, stream, &path);
-            Ok(()) 
-        }
-        else {
-            Err(Box::new(error::ArgError::new("Expected 1 argument")))
+    // A connection to one server that can be opened again, with everything that was set up on it
+    pub struct Session {
+        pub target: Target,
+        pub stream: TcpStream,
+        pub transmitter: FileTransmitter,
+        pub receiver: FileReceiver,
+        pub limiter: Limiter,
+        pub policy: RetryPolicy,
+        // The shell's working directory on the server, the served directory when empty
+        pub remote_dir: String,
+        // Reconnects made by run so far
+        retries: u32,
+        // Left mid-reply by a quick request, run starts over on a new connection
+        broken: bool
+    }
+
+    fn open_with_retries(target: &Target, policy: &RetryPolicy) -> io::Result<TcpStream> {
+        let mut backoff = Backoff::new(policy);
+        loop {
+            let error = match target.connect(policy.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) if retry::is_retryable(&e) => e,
+                Err(e) => return Err(e)
+            };
+            match backoff.next_delay() {
+                Some(delay) => {
+                    note!("Unable to reach {}: {}, retrying in {:.1}s", target, error, delay.as_secs_f32());
+                    thread::sleep(delay);
+                },
+                None => return Err(error)
+            }
         }
     }
 
-    fn download(receiver: &mut FileReceiver, args: Vec<&str>, stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
-        if args.len() == 1 {
-            commands::download(receiver, stream, args[0]);
-            Ok(()) 
+    impl Session {
+        pub fn connect(target: Target, policy: RetryPolicy) -> io::Result<Session> {
+            let stream = open_with_retries(&target, &policy)?;
+            Session::new(target, stream, policy)
         }
-        else {
-            Err(Box::new(error::ArgError::new("Expected 1 argument")))
+
+        pub fn new(target: Target, stream: TcpStream, policy: RetryPolicy) -> io::Result<Session> {
+            stream.set_read_timeout(policy.timeout)?;
+            stream.set_write_timeout(policy.timeout)?;
+
+            let limiter = Limiter::unlimited();
+            let mut transmitter = FileTransmitter::new();
+            let mut receiver = FileReceiver::new();
+            transmitter.throttle(limiter.clone());
+            receiver.throttle(limiter.clone());
+
+            Ok(Session { target, stream, transmitter, receiver, limiter, policy, remote_dir: String::new(), retries: 0, broken: false })
+        }
+
+        // Joins path onto the working directory, a leading / starts from the served directory
+        pub fn remote_path(&self, path: &str) -> String {
+            let mut parts: Vec<&str> = if path.starts_with('/') { Vec::new() } else { self.remote_dir.split('/').collect() };
+            for part in path.split('/') {
+                match part {
+                    "" | "." => {},
+                    // Like a shell, going up from the top stays there
+                    ".." => { parts.pop(); },
+                    part => parts.push(part)
+                }
+            }
+            parts.retain(|p| !p.is_empty());
+            parts.join("/")
+        }
+
+        // There is no login, getting back to where we were means replaying the limit and compression
+        fn handshake(&mut self) -> io::Result<()> {
+            if self.limiter.rate().is_some() {
+                commands::limit(&self.limiter, &mut self.stream, self.limiter.rate())?;
+            }
+            let codec = self.transmitter.codec();
+            commands::negotiate(&mut self.transmitter, &mut self.receiver, &mut self.stream, codec)?;
+            Ok(())
+        }
+
+        fn replace_stream(&mut self, stream: TcpStream) -> io::Result<()> {
+            stream.set_read_timeout(self.policy.timeout)?;
+            stream.set_write_timeout(self.policy.timeout)?;
+            self.stream = stream;
+            self.broken = false;
+            self.handshake()
+        }
+
+        pub fn reconnect(&mut self) -> io::Result<()> {
+            let stream = open_with_retries(&self.target, &self.policy)?;
+            self.replace_stream(stream)
+        }
+
+        // Runs a transfer like run and reports it, failures only go to the transfer log
+        pub fn transfer<F>(&mut self, kind: &str, local: &str, remote: &str, op: F) -> io::Result<()>
+        where F: FnMut(&mut FileTransmitter, &mut FileReceiver, &mut TcpStream) -> io::Result<TransferStats> {
+            let mut failed = TransferStats::new();
+            let retries = self.retries;
+            match self.run(op) {
+                Ok(mut stats) => {
+                    stats.set_retries(self.retries - retries);
+                    output::transfer(kind, local, remote, &stats);
+                    Ok(())
+                },
+                Err(e) => {
+                    failed.set_retries(self.retries - retries);
+                    failed.fail(&e);
+                    output::log_transfer(kind, local, remote, &failed);
+                    Err(e)
+                }
+            }
         }
+
+        // For asides like completion: no retries and a short timeout instead of the usual one
+        // A failure may leave part of the reply unread, so the connection isn't used again
+        pub fn quick<T, F>(&mut self, timeout: Duration, op: F) -> io::Result<T>
+        where F: FnOnce(&mut FileTransmitter, &mut FileReceiver, &mut TcpStream) -> io::Result<T> {
+            if self.broken {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "Waiting to reconnect"));
+            }
+            self.stream.set_read_timeout(Some(timeout))?;
+            let result = op(&mut self.transmitter, &mut self.receiver, &mut self.stream);
+            let restored = self.stream.set_read_timeout(self.policy.timeout);
+            if result.is_err() || restored.is_err() {
+                self.broken = true;
+            }
+            result
+        }
+
+        // Like run, telling op when it is sending its request again on a new connection
+        pub fn replaying<T, F>(&mut self, mut op: F) -> io::Result<T>
+        where F: FnMut(&mut FileTransmitter, &mut FileReceiver, &mut TcpStream, bool) -> io::Result<T> {
+            let mut replay = false;
+            self.run(|transmitter, receiver, stream| {
+                let result = op(transmitter, receiver, stream, replay);
+                replay = true;
+                result
+            })
+        }
+
+        // Runs op, and again on a new connection if the old one fails under it
+        pub fn run<T, F>(&mut self, mut op: F) -> io::Result<T>
+        where F: FnMut(&mut FileTransmitter, &mut FileReceiver, &mut TcpStream) -> io::Result<T> {
+            if self.broken {
+                self.reconnect()?;
+            }
+            let mut backoff = Backoff::new(&self.policy);
+            loop {
+                let mut error = match op(&mut self.transmitter, &mut self.receiver, &mut self.stream) {
+                    Ok(value) => return Ok(value),
+                    Err(e) if retry::is_retryable(&e) => e,
+                    Err(e) => return Err(e)
+                };
+
+                // Reconnecting counts against the same retries
+                loop {
+                    let delay = match backoff.next_delay() {
+                        Some(delay) => delay,
+                        None => return Err(error)
+                    };
+                    note!("Connection problem: {}, retrying in {:.1}s", error, delay.as_secs_f32());
+                    thread::sleep(delay);
+                    self.retries += 1;
+
+                    match self.target.connect(self.policy.timeout).and_then(|stream| self.replace_stream(stream)) {
+                        Ok(()) => break,
+                        Err(e) if retry::is_retryable(&e) => error = e,
+                        Err(e) => return Err(e)
+                    }
+                }
+            }
+        }
+    }
+}
+
+mod editor {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use rustyline::completion::{Completer, Pair};
+    use rustyline::error::ReadlineError;
+    use rustyline::highlight::Highlighter;
+    use rustyline::hint::Hinter;
+    use rustyline::history::FileHistory;
+    use rustyline::validate::Validator;
+    use rustyline::{CompletionType, Config, Context, Editor, Helper};
+    use crate::net::client::commands;
+    use crate::net::client::session::Session;
+    use crate::net::client::shell::{COMMANDS, PRE_COMMANDS};
+
+    // Commands taking paths on this machine, and on the server
+    const LOCAL: &[&str] = &["upload", "mput", "lcd", "lls"];
+    const REMOTE: &[&str] = &["download", "mget", "delete", "mdelete", "dir", "cd", "rename"];
+    const DIRS_ONLY: &[&str] = &["cd", "lcd", "dir", "lls"];
+    // Long enough for a big directory, short enough that Tab on a dead server comes back quickly
+    const COMPLETION_TIMEOUT: Duration = Duration::from_secs(2);
+
+    pub type ShellEditor = Editor<ShellHelper, FileHistory>;
+
+    pub struct ShellHelper {
+        // None until connected
+        session: Option<RefCell<Session>>,
+        profiles: Vec<String>,
+        // Remote directories already listed for completion, by their full path
+        listings: RefCell<HashMap<String, Vec<(String, bool)>>>
     }
 
-    fn delete(args: Vec<&str>, receiver: &mut FileReceiver, stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
-        if args.len() == 1 {
-            commands::delete(receiver, stream, args[0]);
-            Ok(()) 
+    impl ShellHelper {
+        pub fn new(profiles: Vec<String>) -> ShellHelper {
+            ShellHelper { session: None, profiles, listings: RefCell::new(HashMap::new()) }
         }
-        else {
-            Err(Box::new(error::ArgError::new("Expected 1 argument")))
+
+        pub fn connected(session: Session) -> ShellHelper {
+            ShellHelper { session: Some(RefCell::new(session)), profiles: Vec::new(), listings: RefCell::new(HashMap::new()) }
+        }
+
+        pub fn session(&mut self) -> &mut Session {
+            self.session.as_mut().expect("Not connected").get_mut()
+        }
+
+        // Any command may have changed the server, listings are fetched again afterwards
+        pub fn forget(&mut self) {
+            self.listings.get_mut().clear();
+        }
+
+        fn remote_entries(&self, dir: &str) -> Vec<(String, bool)> {
+            let mut session = match self.session.as_ref() {
+                Some(session) => session.borrow_mut(),
+                None => return Vec::new()
+            };
+            let path = session.remote_path(dir);
+            let mut listings = self.listings.borrow_mut();
+            if !listings.contains_key(&path) {
+                // Completion is best effort, a failure just offers nothing until the next command reconnects
+                match session.quick(COMPLETION_TIMEOUT, |_, receiver, stream| commands::list_shallow(receiver, stream, &path)) {
+                    Ok(entries) => { listings.insert(path.clone(), entries.into_iter().map(|e| (e.path, e.is_dir)).collect()); },
+                    Err(_) => return Vec::new()
+                }
+            }
+            listings[&path].clone()
         }
     }
 
-    fn dir(args: Vec<&str>, receiver: &mut FileReceiver, stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
-        if args.is_empty() {
-            commands::dir(receiver, stream);
-            Ok(()) 
+    fn local_entries(dir: &str) -> Vec<(String, bool)> {
+        let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new()
+        };
+        entries.filter_map(|e| e.ok())
+            .map(|e| (e.file_name().to_string_lossy().into_owned(), e.file_type().map(|t| t.is_dir()).unwrap_or(false)))
+            .collect()
+    }
+
+    // Where the word being typed starts, and the quote it is still inside of
+    fn word_start(line: &str) -> (usize, Option<char>) {
+        let mut start = 0;
+        let mut quote = None;
+        let mut escaped = false;
+        for (i, c) in line.char_indices() {
+            if escaped {
+                escaped = false;
+                continue;
+            }
+            match (quote, c) {
+                (None, '\\') | (Some('"'), '\\') => escaped = true,
+                (None, '\'') | (None, '"') => quote = Some(c),
+                (Some(q), c) if c == q => quote = None,
+                (None, c) if c.is_whitespace() => start = i + c.len_utf8(),
+                _ => {}
+            }
         }
-        else {
-            Err(Box::new(error::ArgError::new("Expected 0 arguments")))
+        (start, quote)
+    }
+
+    // What a partly typed word stands for, closing any quote left open
+    fn unquote(word: &str) -> String {
+        ["", "'", "\""].iter()
+            .find_map(|close| shell_words::split(&format!("{}{}", word, close)).ok())
+            .map(|words| words.concat())
+            .unwrap_or_default()
+    }
+
+    impl Completer for ShellHelper {
+        type Candidate = Pair;
+
+        fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+            let line = &line[..pos];
+            let (start, _) = word_start(line);
+            let word = &line[start..];
+
+            if line[..start].trim().is_empty() {
+                let commands = if self.session.is_some() { COMMANDS } else { PRE_COMMANDS };
+                let candidates = commands.iter()
+                    .filter(|c| c.starts_with(word))
+                    .map(|c| Pair { display: String::from(*c), replacement: format!("{} ", c) })
+                    .collect();
+                return Ok((start, candidates));
+            }
+
+            let command = line.split_whitespace().next().unwrap_or("").to_ascii_lowercase();
+            // Only the last part of a path is replaced, the rest stays as it was typed
+            let split = word.rfind('/').map(|i| i + 1).unwrap_or(0);
+            let (dir, prefix) = (unquote(&word[..split]), unquote(&word[split..]));
+            let dir = dir.as_str();
+            let prefix = prefix.as_str();
+            // A quote opened before the last / stays open, one opened after it is replaced too
+            let (_, quote) = word_start(&line[..start + split]);
+            let entries = if self.session.is_none() {
+                if command == "connect" { self.profiles.iter().map(|p| (p.clone(), false)).collect() } else { Vec::new() }
+            }
+            else if LOCAL.contains(&command.as_str()) {
+                local_entries(dir)
+            }
+            else if REMOTE.contains(&command.as_str()) {
+                self.remote_entries(dir)
+            }
+            else {
+                Vec::new()
+            };
+
+            // Hidden files only once asked for
+            let dirs_only = DIRS_ONLY.contains(&command.as_str());
+            let mut candidates: Vec<Pair> = entries.into_iter()
+                .filter(|(name, is_dir)| name.starts_with(prefix) && (prefix.starts_with('.') || !name.starts_with('.')) && (*is_dir || !dirs_only))
+                .map(|(name, is_dir)| {
+                    // Inside that open quote the name goes in as is, otherwise it is quoted if it needs to be
+                    let replacement = match quote {
+                        Some(_) => name.clone(),
+                        None => shell_words::quote(&name).into_owned()
+                    };
+                    let suffix = if is_dir { "/" } else { "" };
+                    Pair { display: name + suffix, replacement: replacement + suffix }
+                })
+                .collect();
+            candidates.sort_by(|a, b| a.display.cmp(&b.display));
+            Ok((start + split, candidates))
         }
     }
 
-    // Connection handling
-    fn connect(args: Vec<&str>, connection: &mut net::Connection) -> Result<(), Box<dyn Error>> {
-        if args.len() == 2 {
-            let ip: std::net::IpAddr = args[0].parse().unwrap();
-            let port: u16 = args[1].parse().unwrap();
+    impl Hinter for ShellHelper {
+        type Hint = String;
+    }
+
+    impl Highlighter for ShellHelper {}
+
+    impl Validator for ShellHelper {}
+
+    impl Helper for ShellHelper {}
+
+    // Shared by both shells and kept between runs
+    fn history_path() -> Option<PathBuf> {
+        let dir = dirs::data_dir()?.join("netfolder");
+        fs::create_dir_all(&dir).ok()?;
+        Some(dir.join("history"))
+    }
 
-            commands::connect(connection, ip, port);
-            Ok(()) 
+    pub fn new(helper: ShellHelper) -> ShellEditor {
+        // Tab lists the choices like bash rather than cycling through them
+        let config = Config::builder().completion_type(CompletionType::List).build();
+        let mut editor = ShellEditor::with_config(config).expect("Unable to set up the terminal");
+        editor.set_helper(Some(helper));
+        if let Some(path) = history_path() {
+            editor.load_history(&path).ok();
         }
-        else {
-            Err(Box::new(error::ArgError::new("Expected 2 arguments")))
+        editor
+    }
+
+    // The next line typed, None once input ends
+    pub fn read_line(editor: &mut ShellEditor, prompt: &str) -> Option<String> {
+        loop {
+            match editor.readline(prompt) {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        editor.add_history_entry(line.as_str()).ok();
+                        if let Some(path) = history_path() {
+                            editor.append_history(&path).ok();
+                        }
+                    }
+                    return Some(line);
+                },
+                // Ctrl-C drops the line, like a shell
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => return None,
+                Err(e) => {
+                    colour::red_ln!("{}", e);
+                    return None;
+                }
+            }
         }
     }
+}
+
+mod shell {
+    use std::collections::HashMap;
+    use std::io::BufRead;
+    use std::error::Error;
+    use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind};
+    use crate::net::client::{self, error, commands};
+    use crate::net::client::editor::{self, ShellHelper};
+    use crate::net::client::session::Session;
+    use crate::net;
+    use crate::throttle;
+    use crate::compression::Codec;
+    use crate::config::{Config, Profile};
+    use crate::output;
+    use serde_json::json;
+
+    pub const PRE_COMMANDS: &[&str] = &["connect", "exit", "help"];
+    pub const COMMANDS: &[&str] = &["cd", "compress", "delete", "dir", "download", "exit", "help", "lcd", "limit", "lls", "lpwd", "mdelete", "mget", "mput", "pwd", "rename", "stats", "upload"];
+
+    // Command definitions, parsed the same way as the command line
+    fn command(name: &str, about: &'static str) -> App<'static> {
+        App::new(name)
+            .about(about)
+            .setting(AppSettings::DisableVersion)
+            .setting(AppSettings::ColorNever)
+            .help_template("{bin} - {about}\n\nUSAGE:\n    {usage}\n\n{all-args}")
+    }
 
-    // Command parsing and running
-    fn pre_run_command(connection: &mut net::Connection, command: &str, args: Vec<&str>) -> Result<(), Box<dyn Error>> {
-        match command {
-            "connect" => { connect(args, connection) },
-            _ => { println!("Not connected, invalid command"); Ok(()) }
+    fn delta() -> Arg<'static> {
+        Arg::new("delta")
+            .long("delta")
+            .takes_value(false)
+            .about("Only send the parts of the file that changed")
+    }
+
+    fn app(name: &str) -> Option<App<'static>> {
+        let app = match name {
+            "connect" => command("connect", "Connect to a server")
+                .arg(Arg::new("target").required(true).index(1).about("A profile, host, host:port or [v6 address]:port"))
+                .arg(Arg::new("port").index(2).about("The port, if the target doesn't give one")),
+            "upload" | "mput" => command(name, "Upload files into the remote directory")
+                .arg(Arg::new("files").required(true).multiple(true).index(1).about("The local files, wildcards like *.log are allowed"))
+                .arg(delta()),
+            "download" | "mget" => command(name, "Download files into the local directory")
+                .arg(Arg::new("paths").required(true).multiple(true).index(1).about("The remote files, wildcards are allowed in the last part"))
+                .arg(delta()),
+            "delete" | "mdelete" => command(name, "Delete remote files or empty directories")
+                .arg(Arg::new("paths").required(true).multiple(true).index(1).about("The remote paths, wildcards are allowed in the last part"))
+                .arg(Arg::new("yes").short('y').long("yes").takes_value(false).about("Don't ask before deleting what a wildcard matched")),
+            "dir" => command("dir", "List a remote directory")
+                .arg(Arg::new("path").index(1).about("The remote directory, the current one if left out")),
+            "rename" => command("rename", "Rename or move a remote file")
+                .arg(Arg::new("from").required(true).index(1).about("The current remote path"))
+                .arg(Arg::new("to").required(true).index(2).about("The new remote path")),
+            "cd" => command("cd", "Change the remote directory")
+                .arg(Arg::new("dir").index(1).about("The remote directory, the served directory if left out")),
+            "pwd" => command("pwd", "Print the remote directory"),
+            "lcd" => command("lcd", "Change the local directory")
+                .arg(Arg::new("dir").required(true).index(1).about("The local directory")),
+            "lpwd" => command("lpwd", "Print the local directory"),
+            "lls" => command("lls", "List a local directory")
+                .arg(Arg::new("dir").index(1).about("The local directory, the current one if left out")),
+            "limit" => command("limit", "Show or set the bandwidth cap")
+                .arg(Arg::new("rate").index(1).about("A rate like 10M, or off")),
+            "compress" => command("compress", "Show or set the compression")
+                .arg(Arg::new("codec").index(1).possible_values(&["none", "zstd", "lz4"]).about("The codec to ask the server for")),
+            "stats" => command("stats", "Show what the server has done, for administrators"),
+            "help" => command("help", "Describe the commands")
+                .arg(Arg::new("command").index(1).about("The command to describe")),
+            "exit" => command("exit", "Leave the shell"),
+            _ => return None
+        };
+        Some(app)
+    }
+
+    // None when only help was asked for, bad arguments come back with the usage
+    fn parse(command: &str, args: &[String], known: &[&str]) -> Result<Option<ArgMatches>, Box<dyn Error>> {
+        let app = match app(command).filter(|_| known.contains(&command)) {
+            Some(app) => app,
+            None => return Err(Box::new(error::ArgError::new(&format!("Unknown command {}, try help", command))))
+        };
+
+        match app.try_get_matches_from(std::iter::once(command).chain(args.iter().map(|a| a.as_str()))) {
+            Ok(matches) => Ok(Some(matches)),
+            Err(e) if e.kind == ErrorKind::DisplayHelp => {
+                print!("{}", e);
+                Ok(None)
+            },
+            Err(e) => Err(Box::new(error::ArgError::new(e.to_string().trim_end())))
         }
     }
 
-    fn run_command(transmitter: &mut FileTransmitter, receiver: &mut FileReceiver, stream: &mut TcpStream, command: &str, args: Vec<&str>) -> Result<(), Box<dyn Error>> {
-        match command {
-            "upload" => { upload(transmitter, args, stream) },
-            "download" => { download(receiver, args, stream) },
-            "delete" => { delete(args, receiver, stream) },
-            "dir" => { dir(args, receiver, stream) }
-            _ => { println!("Connected, Invalid command"); Ok(()) }
+    //Commands
+    fn help(args: &ArgMatches, known: &[&str]) -> Result<(), Box<dyn Error>> {
+        match args.value_of("command") {
+            Some(name) => match app(name).filter(|_| known.contains(&name)) {
+                Some(mut app) => {
+                    app.print_help()?;
+                    println!();
+                    Ok(())
+                },
+                None => Err(Box::new(error::ArgError::new(&format!("Unknown command {}", name))))
+            },
+            None => {
+                for name in known.iter() {
+                    let app = app(name).unwrap();
+                    println!("  {:<10}{}", name, app.get_about().unwrap_or(""));
+                }
+                println!("Use help <command> or <command> --help for its arguments");
+                Ok(())
+            }
         }
     }
 
+    fn upload(args: &ArgMatches, session: &mut Session) -> Result<(), Box<dyn Error>> {
+        let files: Vec<&str> = args.values_of("files").unwrap().collect();
+        client::upload_matching(session, &files, args.is_present("delta"))?;
+        Ok(()) 
+    }
 
-    fn parse_command(command: &'_ str) -> (String, Vec<&'_ str>) {
-        let split = command.trim().split(' ').collect::<Vec<&str>>();
+    fn download(args: &ArgMatches, session: &mut Session) -> Result<(), Box<dyn Error>> {
+        let paths: Vec<&str> = args.values_of("paths").unwrap().collect();
+        client::download_matching(session, &paths, args.is_present("delta"))?;
+        Ok(()) 
+    }
 
-        if split.is_empty() {
-            (String::new(), split)
+    fn delete(args: &ArgMatches, session: &mut Session) -> Result<(), Box<dyn Error>> {
+        let paths: Vec<&str> = args.values_of("paths").unwrap().collect();
+        client::delete_matching(session, &paths, args.is_present("yes"))?;
+        Ok(()) 
+    }
+
+    fn dir(args: &ArgMatches, session: &mut Session) -> Result<(), Box<dyn Error>> {
+        let path = session.remote_path(args.value_of("path").unwrap_or(""));
+        session.run(|_, receiver, stream| commands::dir(receiver, stream, &path))?;
+        Ok(()) 
+    }
+
+    // Working directories, the remote one only exists here and is joined onto every remote path
+    fn cd(args: &ArgMatches, session: &mut Session) -> Result<(), Box<dyn Error>> {
+        let path = session.remote_path(args.value_of("dir").unwrap_or("/"));
+        if !session.run(|_, receiver, stream| commands::is_dir(receiver, stream, &path))? {
+            return Err(Box::new(error::ArgError::new(&format!("No such directory /{}", path))));
         }
-        else {
-            let mut first = String::from(split[0].trim());
-            first.make_ascii_lowercase();
+        session.remote_dir = path;
+        Ok(())
+    }
 
-            if split.len() == 1 {
-                (first, Vec::new())
+    fn pwd(session: &mut Session) -> Result<(), Box<dyn Error>> {
+        output::result(format!("/{}", session.remote_dir), json!({"type": "pwd", "path": format!("/{}", session.remote_dir)}));
+        Ok(())
+    }
+
+    fn lcd(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+        std::env::set_current_dir(args.value_of("dir").unwrap())?;
+        Ok(())
+    }
+
+    fn lpwd() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::current_dir()?;
+        output::result(dir.display(), json!({"type": "lpwd", "path": dir}));
+        Ok(())
+    }
+
+    fn lls(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+        let dir = args.value_of("dir").unwrap_or(".");
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            names.push(if entry.file_type()?.is_dir() { name + "/" } else { name });
+        }
+        names.sort();
+        output::result(names.join("\n"), json!({"type": "local_listing", "path": dir, "names": names}));
+        Ok(())
+    }
+
+    fn rename(args: &ArgMatches, session: &mut Session) -> Result<(), Box<dyn Error>> {
+        let (from, to) = (session.remote_path(args.value_of("from").unwrap()), session.remote_path(args.value_of("to").unwrap()));
+        session.replaying(|_, receiver, stream, replay| commands::rename(receiver, stream, &from, &to, replay))?;
+        Ok(())
+    }
+
+    fn limit(args: &ArgMatches, session: &mut Session) -> Result<(), Box<dyn Error>> {
+        match args.value_of("rate") {
+            None => {
+                show_limit(session.limiter.rate());
+                Ok(())
+            },
+            Some(rate) => match throttle::parse_rate(rate) {
+                Some(rate) => {
+                    let limiter = session.limiter.clone();
+                    session.run(|_, _, stream| commands::limit(&limiter, stream, rate))?;
+                    show_limit(rate);
+                    Ok(())
+                },
+                None => Err(Box::new(error::ArgError::new("Expected a rate like 10M, or off")))
             }
-            else {
-                (first, split.into_iter().skip(1).collect::<Vec<&str>>())
+        }
+    }
+
+    fn show_limit(rate: Option<u64>) {
+        output::result(format!("Bandwidth limit: {}", throttle::format_rate(rate)), json!({"type": "limit", "rate": rate}));
+    }
+
+    pub fn show_compression(codec: Codec) {
+        output::result(format!("Compression: {}", codec.name()), json!({"type": "compression", "codec": codec.name()}));
+    }
+
+    fn compress(args: &ArgMatches, session: &mut Session) -> Result<(), Box<dyn Error>> {
+        match args.value_of("codec") {
+            None => show_compression(session.transmitter.codec()),
+            Some(codec) => {
+                let codec = Codec::from_name(codec).unwrap();
+                let codec = session.run(|transmitter, receiver, stream| commands::negotiate(transmitter, receiver, stream, codec))?;
+                show_compression(codec);
             }
         }
+        Ok(())
     }
 
-    // Shell loop
-    fn client_prompt(name: &str) {
-        print!("({}) > ", name);
-        io::stdout().flush().unwrap();
+    // Connection handling
+    // A profile name, with or without @, or a host and optional port
+    fn connect(args: &ArgMatches, connection: &mut net::Connection, config: &Config, profile: &mut Profile) -> Result<(), Box<dyn Error>> {
+        let target = args.value_of("target").unwrap();
+        let port = match args.value_of("port") {
+            Some(port) => Some(port.parse()?),
+            None => None
+        };
+
+        let name = target.trim_start_matches('@');
+        let target = if target.starts_with('@') || (port.is_none() && config.profiles.contains_key(name)) {
+            let found = config.profile(name)?;
+            *profile = found.clone();
+            net::Target::parse(found.host.as_ref().unwrap(), found.port)?
+        }
+        else {
+            *profile = Profile::default();
+            net::Target::parse(target, port)?
+        };
+
+        commands::connect(connection, target)?;
+        Ok(()) 
+    }
+
+    // Command parsing and running
+    fn pre_run_command(connection: &mut net::Connection, config: &Config, profile: &mut Profile, command: &str, args: &[String]) -> Result<(), Box<dyn Error>> {
+        let args = match parse(command, args, PRE_COMMANDS)? {
+            Some(args) => args,
+            None => return Ok(())
+        };
+        match command {
+            "connect" => { connect(&args, connection, config, profile) },
+            "help" => { help(&args, PRE_COMMANDS) },
+            _ => { Ok(()) }
+        }
+    }
+
+    fn run_command(session: &mut Session, command: &str, args: &[String]) -> Result<(), Box<dyn Error>> {
+        let args = match parse(command, args, COMMANDS)? {
+            Some(args) => args,
+            None => return Ok(())
+        };
+        match command {
+            "upload" | "mput" => { upload(&args, session) },
+            "download" | "mget" => { download(&args, session) },
+            "delete" | "mdelete" => { delete(&args, session) },
+            "dir" => { dir(&args, session) },
+            "rename" => { rename(&args, session) },
+            "cd" => { cd(&args, session) },
+            "pwd" => { pwd(session) },
+            "lcd" => { lcd(&args) },
+            "lpwd" => { lpwd() },
+            "lls" => { lls(&args) },
+            "limit" => { limit(&args, session) },
+            "compress" => { compress(&args, session) },
+            "stats" => { client::show_stats(session)?; Ok(()) },
+            "help" => { help(&args, COMMANDS) },
+            _ => { Ok(()) }
+        }
+    }
+
+    // Splits a line like a shell would, quotes and backslashes keep spaces in arguments
+    fn parse_command(line: &str) -> Option<(String, Vec<String>)> {
+        let words = match shell_words::split(line) {
+            Ok(words) => words,
+            Err(e) => {
+                output::error(e);
+                return None;
+            }
+        };
+        let (command, args) = words.split_first()?;
+        Some((command.to_ascii_lowercase(), args.to_vec()))
     }
 
-    pub fn pre_connection_shell() -> net::Connection {
+    // Shell loop
+    // The connection and the profile it was made with, if any
+    pub fn pre_connection_shell(config: &Config) -> (net::Connection, Profile) {
+        let mut editor = editor::new(ShellHelper::new(config.profiles.keys().cloned().collect()));
         let mut connection = net::Connection::default();
+        let mut profile = Profile::default();
         while !connection.connected() {
-            client_prompt("not-connected");
-            let mut line = String::new();
-            io::stdin()
-                .read_line(&mut line)
-                .expect("Failed to read line");
+            let line = match editor::read_line(&mut editor, "(not-connected) > ") {
+                Some(line) => line,
+                None => std::process::exit(0)
+            };
 
-            let (command, args) = parse_command(&line);
-            match pre_run_command(&mut connection, &command, args) {
-                Ok(()) => {},
-                Err(e)  => { colour::red_ln!("{:?}", e)}
+            let (command, args) = match parse_command(&line) {
+                Some(parsed) => parsed,
+                None => continue
+            };
+            if command == "exit" {
+                std::process::exit(0);
+            }
+            if let Err(e) = pre_run_command(&mut connection, config, &mut profile, &command, &args) {
+                output::error(e);
             }
         }
 
-        connection
+        (connection, profile)
     }
-    pub fn post_connection_shell(stream: TcpStream, transmitter: FileTransmitter, receiver: FileReceiver) {
-        let mut stream  = stream;
-        let mut transmitter = transmitter;
-        let mut receiver = receiver;
 
+    pub fn post_connection_shell(session: Session) {
+        let mut editor = editor::new(ShellHelper::connected(session));
         loop {
-            client_prompt("Connected");
-            let mut line = String::new();
-            io::stdin()
-                .read_line(&mut line)
-                .expect("Failed to read line");
+            let prompt = format!("(Connected /{}) > ", editor.helper_mut().unwrap().session().remote_dir);
+            let line = match editor::read_line(&mut editor, &prompt) {
+                Some(line) => line,
+                None => break
+            };
 
-            let (command, args) = parse_command(&line);
+            let (command, args) = match parse_command(&line) {
+                Some(parsed) => parsed,
+                None => continue
+            };
             if command == "exit" {
                 break;
             }
-            match run_command(&mut transmitter, &mut receiver, &mut stream, &command, args) {
-                Ok(()) => {},
-                Err(e)  => { colour::red_ln!("{:?}", e)}
+            let helper = editor.helper_mut().unwrap();
+            if let Err(e) = run_command(helper.session(), &command, &args) {
+                output::error(e);
+            }
+            helper.forget();
+        }
+        let session = editor.helper_mut().unwrap().session();
+        commands::disconnect(&mut session.stream, &mut session.transmitter, &mut session.receiver).ok();
+    }
+
+    // Batch scripts
+    // Replaces $NAME and ${NAME} with a script variable or the environment before the line is split
+    // Single quotes and a backslash keep a $ as it is, and so does $$
+    fn expand(line: &str, vars: &HashMap<String, String>) -> Result<String, Box<dyn Error>> {
+        let mut expanded = String::new();
+        let mut chars = line.chars().peekable();
+        let (mut single, mut double) = (false, false);
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' if !double => single = !single,
+                '"' if !single => double = !double,
+                '\\' if !single => {
+                    expanded.push(c);
+                    expanded.extend(chars.next());
+                    continue;
+                },
+                '$' if !single => {
+                    let name: String = match chars.peek() {
+                        Some('$') => {
+                            chars.next();
+                            expanded.push('$');
+                            continue;
+                        },
+                        Some('{') => {
+                            chars.next();
+                            let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                            name
+                        },
+                        _ => {
+                            let mut name = String::new();
+                            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                                name.push(c);
+                            }
+                            name
+                        }
+                    };
+                    if name.is_empty() {
+                        expanded.push('$');
+                        continue;
+                    }
+                    // Quoted so a value with spaces or quotes in it stays one argument
+                    match vars.get(&name).cloned().or_else(|| std::env::var(&name).ok()) {
+                        Some(value) if double => expanded.push_str(&value.replace('\\', "\\\\").replace('"', "\\\"")),
+                        Some(value) => expanded.push_str(&shell_words::quote(&value)),
+                        None => return Err(Box::new(error::ArgError::new(&format!("{} is not set", name))))
+                    }
+                    continue;
+                },
+                _ => {}
             }
+            expanded.push(c);
+        }
+        Ok(expanded)
+    }
+
+    // NAME=value, with the name starting with a letter or _
+    fn assignment(word: &str) -> Option<(&str, &str)> {
+        let (name, value) = word.split_once('=')?;
+        let mut chars = name.chars();
+        let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if valid { Some((name, value)) } else { None }
+    }
+
+    // Some(code) when the script asked to exit
+    fn run_batch_line(session: &mut Session, line: &str, vars: &mut HashMap<String, String>, stop_on_error: &mut bool) -> Result<Option<i32>, Box<dyn Error>> {
+        let words = shell_words::split(&expand(line, vars)?)?;
+        let (command, args) = match words.split_first() {
+            Some(parsed) => parsed,
+            None => return Ok(None)
+        };
+        if let Some((name, value)) = assignment(command).filter(|_| args.is_empty()) {
+            vars.insert(String::from(name), String::from(value));
+            return Ok(None);
+        }
+
+        match command.to_ascii_lowercase().as_str() {
+            "set" => match args.iter().map(|a| a.as_str()).collect::<Vec<_>>().as_slice() {
+                ["-e"] => *stop_on_error = true,
+                ["+e"] => *stop_on_error = false,
+                _ => return Err(Box::new(error::ArgError::new("Expected set -e or set +e")))
+            },
+            "exit" => {
+                let code = match args.first() {
+                    Some(code) => code.parse().map_err(|_| error::ArgError::new(&format!("Invalid exit status {}", code)))?,
+                    None => 0
+                };
+                return Ok(Some(code));
+            },
+            command => run_command(session, command, args)?
+        }
+        Ok(None)
+    }
+
+    // Runs every line like the shell would, returning the exit status for the process
+    pub fn run_batch(session: &mut Session, name: &str, input: impl BufRead) -> i32 {
+        let mut vars = HashMap::new();
+        let mut stop_on_error = false;
+        let mut failed = false;
+        for (number, line) in input.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    output::error(format!("{}: {}", name, e));
+                    return 1;
+                }
+            };
+            match run_batch_line(session, &line, &mut vars, &mut stop_on_error) {
+                Ok(Some(code)) => return code,
+                Ok(None) => {},
+                Err(e) => {
+                    output::error(format!("{}:{}: {}", name, number + 1, e));
+                    failed = true;
+                    if stop_on_error {
+                        return 1;
+                    }
+                }
+            }
+        }
+        if failed { 1 } else { 0 }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::expand;
+        use std::collections::HashMap;
+
+        fn words(line: &str) -> Vec<String> {
+            let vars = [("FILE", "my notes.txt"), ("QUOTE", "say \"hi\"")].iter()
+                .map(|(name, value)| (String::from(*name), String::from(*value)))
+                .collect::<HashMap<_, _>>();
+            shell_words::split(&expand(line, &vars).unwrap()).unwrap()
+        }
+
+        #[test]
+        fn values_stay_one_argument() {
+            assert_eq!(words("upload $FILE backup/${FILE}.bak"), ["upload", "my notes.txt", "backup/my notes.txt.bak"]);
+            assert_eq!(words("upload \"$QUOTE\" $QUOTE"), ["upload", "say \"hi\"", "say \"hi\""]);
+        }
+
+        #[test]
+        fn quoted_and_escaped_dollars_are_kept() {
+            assert_eq!(words("echo '$FILE' '$5' $$5 \\$FILE \"\\$FILE\""), ["echo", "$FILE", "$5", "$5", "$FILE", "$FILE"]);
+        }
+
+        #[test]
+        fn unset_variables_are_errors() {
+            assert!(expand("upload $NETFOLDER_TEST_UNSET", &HashMap::new()).is_err());
         }
-        commands::disconnect(&mut stream, &mut transmitter, &mut receiver);
     }
 }
 
 use crate::net;
-use std::path::Path;
-use crate::encoding::{FileTransmitter, FileReceiver};
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+use crate::throttle;
+use crate::compression::Codec;
+use crate::retry::RetryPolicy;
+use crate::config::{Config, Profile};
+use crate::watcher::{self, Debouncer};
+use crate::{encoding, history, output, stats, sync};
+use self::session::Session;
+
+// How long a file has to go untouched before it is pushed
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+// How many paths the text output of stats lists
+const STATS_PATHS: usize = 10;
+
+// Where a file under root goes on the server
+fn remote_name(root: &Path, path: &Path, remote: &str) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Vec<String> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+    Some(sync::remote_path(remote, &parts.join("/")))
+}
+
+// Wildcards
+fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+// Like a shell, * and ? don't match a leading dot
+const MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: true
+};
+
+fn bad_pattern(pattern: &str, e: glob::PatternError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid pattern {}: {}", pattern, e))
+}
+
+// Files matching a local pattern, a plain path is passed through as it is
+fn local_matches(pattern: &str) -> io::Result<Vec<PathBuf>> {
+    if !is_pattern(pattern) {
+        return Ok(vec![PathBuf::from(pattern)]);
+    }
+    let files: Vec<PathBuf> = glob::glob_with(pattern, MATCH_OPTIONS)
+        .map_err(|e| bad_pattern(pattern, e))?
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file())
+        .collect();
+    if files.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No local files match {}", pattern)));
+    }
+    Ok(files)
+}
+
+// Remote paths matching a pattern, which is checked against a listing of its directory
+fn remote_matches(session: &mut Session, pattern: &str, files_only: bool) -> io::Result<Vec<String>> {
+    let pattern = session.remote_path(pattern);
+    if !is_pattern(&pattern) {
+        return Ok(vec![pattern]);
+    }
+    let (dir, name) = match pattern.rfind('/') {
+        Some(i) => (&pattern[..i], &pattern[i + 1..]),
+        None => ("", pattern.as_str())
+    };
+    if is_pattern(dir) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Wildcards only work in the last part of a remote path, not {}", pattern)));
+    }
+
+    let matcher = glob::Pattern::new(name).map_err(|e| bad_pattern(name, e))?;
+    let entries = session.run(|_, receiver, stream| commands::list_shallow(receiver, stream, dir))?;
+    let mut paths: Vec<String> = entries.iter()
+        .filter(|entry| !(files_only && entry.is_dir) && matcher.matches_with(&entry.path, MATCH_OPTIONS))
+        .map(|entry| sync::remote_path(dir, &entry.path))
+        .collect();
+    if paths.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Nothing on the server matches {}", pattern)));
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+// Asks before something that can't be undone, without a terminal to ask on it takes -y
+fn confirm(question: &str) -> io::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}? Pass -y to confirm without a terminal", question)));
+    }
+    eprint!("{}? [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// Each pattern is expanded and transferred in turn, stopping at the first failure
+fn upload_matching(session: &mut Session, patterns: &[&str], delta: bool) -> io::Result<()> {
+    for pattern in patterns {
+        for path in local_matches(pattern)? {
+            let name = path.file_name().and_then(|n| n.to_str())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Expected a file, got {}", path.display())))?;
+            let name = session.remote_path(name);
+            session.transfer("upload", &path.to_string_lossy(), &name, |transmitter, _, stream| commands::upload(transmitter, stream, &path, &name, delta))?;
+        }
+    }
+    Ok(())
+}
+
+// Saved under their own names in the local directory, like ftp's get
+fn download_matching(session: &mut Session, patterns: &[&str], delta: bool) -> io::Result<()> {
+    for pattern in patterns {
+        for remote in remote_matches(session, pattern, true)? {
+            let local = Path::new(&remote).file_name().and_then(|n| n.to_str()).map(String::from)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Expected a file, got {}", remote)))?;
+            session.transfer("download", &local, &remote, |_, receiver, stream| commands::download(receiver, stream, &remote, &local, delta))?;
+        }
+    }
+    Ok(())
+}
+
+// Everything is matched before anything is deleted, so the whole set can be confirmed at once
+fn delete_matching(session: &mut Session, patterns: &[&str], yes: bool) -> io::Result<()> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        paths.extend(remote_matches(session, pattern, false)?);
+    }
+
+    if !yes && patterns.iter().any(|pattern| is_pattern(pattern)) {
+        for path in paths.iter() {
+            note!("  {}", path);
+        }
+        if !confirm(&format!("Delete {} path{}", paths.len(), if paths.len() == 1 { "" } else { "s" }))? {
+            note!("Nothing deleted");
+            return Ok(());
+        }
+    }
+    for path in paths {
+        session.replaying(|_, receiver, stream, replay| commands::delete(receiver, stream, &path, replay))?;
+    }
+    Ok(())
+}
+
+// Server statistics, the busiest paths first
+fn show_stats(session: &mut Session) -> io::Result<()> {
+    let summary = session.run(|_, _, stream| commands::stats(stream))?;
+    let bytes = |bytes: u64| encoding::format_bytes(bytes as usize);
+    let totals = |totals: &stats::Totals| format!("{} uploads, {} downloads, {} deletes, {} renames, {} failed, {} in, {} out",
+        totals.uploads, totals.downloads, totals.deletes, totals.renames, totals.failures, bytes(totals.bytes_in), bytes(totals.bytes_out));
+
+    let mut text = format!("Since {}: {} connections, {} open now, at most {} at once\n", summary.since, summary.connections, summary.active, summary.peak_connections);
+    text += &format!("Total: {}\n", totals(&summary.totals));
+    text += "Users:\n";
+    for (user, user_totals) in summary.users.iter() {
+        text += &format!("  {}: {}\n", user, totals(user_totals));
+    }
+    let mut paths: Vec<_> = summary.paths.iter().collect();
+    paths.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes_in + totals.bytes_out));
+    text += "Paths:\n";
+    for (path, path_totals) in paths.iter().take(STATS_PATHS) {
+        text += &format!("  {}: {}\n", path, totals(path_totals));
+    }
+    text += "Throughput:";
+    for sample in summary.throughput.iter() {
+        let minute = chrono::DateTime::from_timestamp(sample.minute as i64, 0).map(|t| t.with_timezone(&chrono::Local).format("%H:%M").to_string()).unwrap_or_default();
+        text += &format!("\n  {}  {}/min in, {}/min out", minute, bytes(sample.bytes_in), bytes(sample.bytes_out));
+    }
+
+    let mut value = serde_json::to_value(&summary).map_err(io::Error::other)?;
+    value["type"] = serde_json::json!("stats");
+    output::result(text, value);
+    Ok(())
+}
+
+fn history_path(matches: &clap::ArgMatches) -> io::Result<PathBuf> {
+    match matches.value_of("history-file") {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => history::default_path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No data directory for the history, give --history-file"))
+    }
+}
 
-// Connection handling
-fn open_connection(ip_str: &str, port: u16) -> net::Connection {
-    let ip: std::net::IpAddr = ip_str.parse().unwrap();
-    net::Connection::new(ip, port)
+// Past operations on every server, oldest first
+fn show_history(path: &Path, matches: &clap::ArgMatches) -> io::Result<()> {
+    let filter = history::Filter {
+        server: matches.value_of("server").map(String::from),
+        direction: matches.value_of("direction").map(String::from),
+        path: matches.value_of("path").map(|pattern| glob::Pattern::new(pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid pattern {}: {}", pattern, e)))).transpose()?,
+        since: matches.value_of("since").map(history::parse_since).transpose()?,
+        failed: matches.is_present("failed")
+    };
+    let entries: Vec<_> = history::read(path)?.into_iter().filter(|entry| filter.matches(entry)).collect();
+    let skip = match matches.value_of("last") {
+        Some(last) => entries.len().saturating_sub(last.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid number {}", last)))?),
+        None => 0
+    };
+
+    if entries.is_empty() {
+        note!("Nothing in the history matches");
+    }
+    for entry in entries.iter().skip(skip) {
+        let mut value = serde_json::to_value(entry).map_err(io::Error::other)?;
+        value["type"] = serde_json::json!("history");
+        output::result(entry, value);
+    }
+    Ok(())
+}
+
+// Uploads changes under local as they settle until killed, riding out server restarts
+fn push_watch(session: &mut Session, local: &Path, remote: &str, delta: bool) -> io::Result<()> {
+    let root = local.canonicalize()?;
+    let (_watcher, changes) = watcher::watch(&root)?;
+    let mut debouncer = Debouncer::new(SETTLE_TIME);
+    let mut connected = true;
+    // Whether the server has everything from before the current connection
+    let mut caught_up = false;
+    note!("Watching {} for changes", root.display());
+
+    loop {
+        // The server never speaks first, so anything to read means it went away
+        if connected && net::readable(&session.stream) {
+            note!("Lost connection to {}", session.target);
+            connected = false;
+        }
+
+        if !connected {
+            caught_up = false;
+            match session.reconnect() {
+                Ok(()) => {
+                    note!("Connected to {}", session.target);
+                    connected = true;
+                },
+                Err(e) => {
+                    note!("Unable to reach {}: {}, retrying", session.target, e);
+                    thread::sleep(RECONNECT_DELAY);
+                    continue;
+                }
+            }
+        }
+
+        if !caught_up {
+            // Everything noticed so far is covered by the full comparison
+            while changes.try_recv().is_ok() {}
+            debouncer.clear();
+            match commands::push(&mut session.transmitter, &mut session.receiver, &mut session.stream, &root, remote, delta) {
+                Ok(_actions) => caught_up = true,
+                Err(e) => {
+                    output::error(format!("Push failed: {}", e));
+                    connected = false;
+                    continue;
+                }
+            }
+        }
+
+        match changes.recv_timeout(debouncer.wait()) {
+            Ok(path) => {
+                debouncer.touch(path);
+                while let Ok(path) = changes.try_recv() {
+                    debouncer.touch(path);
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => return Err(io::Error::other("File watcher stopped"))
+        }
+
+        for path in debouncer.ready() {
+            let name = match remote_name(&root, &path, remote) {
+                Some(name) => name,
+                None => continue
+            };
+
+            // Directories moved in whole only report themselves
+            let result = if path.is_dir() {
+                commands::push(&mut session.transmitter, &mut session.receiver, &mut session.stream, &path, &name, delta).map(|_| ())
+            }
+            else if path.is_file() {
+                output::action(&sync::TwoWayAction::Upload(name.clone()));
+                commands::upload_and_report(&mut session.transmitter, &mut session.stream, &path, &name, delta)
+            }
+            else {
+                continue;
+            };
+
+            // Catching up after reconnecting picks up whatever this missed
+            if let Err(e) = result {
+                output::error(format!("Upload of {} failed: {}", name, e));
+                connected = false;
+                break;
+            }
+        }
+    }
+}
+
+// Command line runs stop at the first failure
+fn or_exit<T>(result: io::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        // JSON errors are already marked as such
+        if output::json() { output::error(e) } else { output::error(format!("Error: {}", e)) }
+        std::process::exit(1);
+    })
 }
 
 // Start the client
 pub fn start_client(matches: &clap::ArgMatches) {
-    let connection = if matches.is_present("host") && matches.is_present("port") {
-        let host = matches.value_of("host").unwrap();
-        let port: u16 = matches.value_of("port").unwrap().parse().expect("Please provide a valid port");
-        open_connection(host, port)
+    output::set_mode(if matches.value_of("output") == Some("json") { output::Mode::Json } else { output::Mode::Text });
+    if let Some(path) = matches.value_of("transfer-log") {
+        or_exit(output::open_transfer_log(Path::new(path)));
     }
-    else {
-        shell::pre_connection_shell()
+    // Only reads the local history, so there's nothing to connect to
+    if let Some(history_matches) = matches.subcommand_matches("history") {
+        or_exit(show_history(&or_exit(history_path(matches)), history_matches));
+        return;
+    }
+    let config = or_exit(Config::load(matches.value_of("config").map(Path::new)));
+    let name = match (matches.value_of("profile"), matches.value_of("at-profile")) {
+        (Some(name), _) => Some(name),
+        (None, Some(name)) => match name.strip_prefix('@') {
+            Some(name) => Some(name),
+            None => or_exit(Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Expected @profile, got {}", name))))
+        },
+        (None, None) => None
+    };
+    let mut profile = match name {
+        Some(name) => or_exit(config.profile(name)).clone(),
+        None => Profile::default()
     };
-    let mut transmitter = FileTransmitter::new();
-    let mut receiver = FileReceiver::new();
-    let mut stream = connection.stream.expect("This should never happen");
 
+    // Options on the command line win over the profile
+    let host = matches.value_of("host").map(String::from).or_else(|| profile.host.clone());
+    // A script can't share stdin with the shell, so it has to know where to connect up front
+    if host.is_none() && matches.is_present("batch") {
+        or_exit::<()>(Err(io::Error::new(io::ErrorKind::InvalidInput, "Batch mode needs a server, give -n or a profile")));
+    }
+    let connection = match host {
+        Some(_) => None,
+        None => {
+            let (connection, shell_profile) = shell::pre_connection_shell(&config);
+            profile = shell_profile;
+            Some(connection)
+        }
+    };
+    for setting in profile.unsupported() {
+        let warning = format!("Ignoring {} in the profile, netfolder has no authentication or encryption yet", setting);
+        if output::json() { colour::e_yellow_ln!("{}", warning) } else { colour::yellow_ln!("{}", warning) }
+    }
+
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let retries: u32 = match (matches.occurrences_of("retries"), profile.retries) {
+        (0, Some(retries)) => retries,
+        _ => or_exit(matches.value_of("retries").unwrap().parse().map_err(|_| invalid("Please provide a valid number of retries")))
+    };
+    let timeout: u64 = match (matches.occurrences_of("timeout"), profile.timeout) {
+        (0, Some(timeout)) => timeout,
+        _ => or_exit(matches.value_of("timeout").unwrap().parse().map_err(|_| invalid("Please provide a valid timeout in seconds")))
+    };
+    let policy = RetryPolicy {
+        retries,
+        timeout: if timeout == 0 { None } else { Some(Duration::from_secs(timeout)) }
+    };
+
+    let mut session = match connection {
+        Some(connection) => or_exit(Session::new(connection.target.expect("This should never happen"), connection.stream.expect("This should never happen"), policy)),
+        None => {
+            let port = matches.value_of("port").map(|port| port.parse().expect("Please provide a valid port")).or(profile.port);
+            or_exit(Session::connect(or_exit(net::Target::parse(&host.unwrap(), port)), policy))
+        }
+    };
+    session.remote_dir = session.remote_path(profile.remote.as_deref().unwrap_or(""));
+
+    // Transfers go ahead without it, the history is only there to look back on
+    if !matches.is_present("no-history") {
+        if let Err(e) = history_path(matches).and_then(|path| history::open(&path, &session.target.to_string())) {
+            colour::e_yellow_ln!("Not keeping a history: {}", e);
+        }
+    }
+
+    if let Some(rate) = matches.value_of("limit").or(profile.limit.as_deref()) {
+        let rate = or_exit(throttle::parse_rate(rate).ok_or_else(|| invalid("Please provide a valid rate, e.g. 10M")));
+        let limiter = session.limiter.clone();
+        or_exit(session.run(|_, _, stream| commands::limit(&limiter, stream, rate)));
+    }
+
+    if let Some(codec) = matches.value_of("compress").or(profile.compress.as_deref()) {
+        let codec = Codec::from_name(codec).unwrap();
+        let codec = or_exit(session.run(|transmitter, receiver, stream| commands::negotiate(transmitter, receiver, stream, codec)));
+        shell::show_compression(codec);
+    }
+
+    let delta = matches.is_present("delta") || profile.delta;
     let mut had_cmd = false;
 
     if matches.is_present("list") {
-        commands::dir(&mut receiver, &mut stream); 
+        let path = session.remote_dir.clone();
+        or_exit(session.run(|_, receiver, stream| commands::dir(receiver, stream, &path))); 
+        had_cmd = true;
+    }
+
+    // A plain path keeps its directories locally, matches land in the current directory
+    if let Some(path) = matches.value_of("download") {
+        if is_pattern(path) {
+            or_exit(download_matching(&mut session, &[path], delta));
+        }
+        else {
+            let remote = session.remote_path(path);
+            or_exit(session.transfer("download", path, &remote, |_, receiver, stream| commands::download(receiver, stream, &remote, path, delta)));
+        }
+        had_cmd = true;
+    }
+
+    if matches.is_present("stats") {
+        or_exit(show_stats(&mut session));
         had_cmd = true;
     }
 
-    if matches.is_present("download") {
-        let path = matches.value_of("download").unwrap();
-        commands::download(&mut receiver, &mut stream, path); 
+    if let Some(pattern) = matches.value_of("upload") {
+        or_exit(upload_matching(&mut session, &[pattern], delta));
         had_cmd = true;
     }
 
-    if matches.is_present("upload") {
-        let path = Path::new(matches.value_of("upload").unwrap());
-        commands::upload(&mut transmitter, &mut stream, &path); 
+    if let Some(pattern) = matches.value_of("delete") {
+        or_exit(delete_matching(&mut session, &[pattern], matches.is_present("yes")));
         had_cmd = true;
     }
 
-    if matches.is_present("delete") {
-        let path = matches.value_of("delete").unwrap();
-        commands::delete(&mut receiver, &mut stream, path); 
+    if let Some(push_matches) = matches.subcommand_matches("push") {
+        let local = Path::new(push_matches.value_of("local").unwrap());
+        let remote = session.remote_path(push_matches.value_of("remote").unwrap_or(""));
+        if push_matches.is_present("watch") {
+            or_exit(push_watch(&mut session, local, &remote, delta));
+            return;
+        }
+        or_exit(session.run(|transmitter, receiver, stream| commands::push(transmitter, receiver, stream, local, &remote, delta)));
+        had_cmd = true;
+    }
+
+    // Watching takes over the connection until the server goes away
+    if let Some(watch_matches) = matches.subcommand_matches("watch") {
+        let path = session.remote_path(watch_matches.value_of("path").unwrap_or(""));
+        or_exit(commands::watch(&mut session.stream, &path));
+        return;
+    }
+
+    if let Some(sync_matches) = matches.subcommand_matches("sync").filter(|m| m.is_present("two-way")) {
+        let local = Path::new(sync_matches.value_of("local").unwrap());
+        let remote = session.remote_path(sync_matches.value_of("remote").unwrap());
+        let dry_run = sync_matches.is_present("dry-run");
+        or_exit(session.run(|transmitter, receiver, stream| commands::sync_two_way(transmitter, receiver, stream, local, &remote, dry_run)));
         had_cmd = true;
     }
+    else if let Some(sync_matches) = matches.subcommand_matches("sync") {
+        let options = commands::SyncOptions {
+            direction: if sync_matches.is_present("pull") { sync::Direction::Pull } else { sync::Direction::Push },
+            compare: if sync_matches.is_present("checksum") { sync::Compare::Checksum } else { sync::Compare::SizeAndTime },
+            delete: sync_matches.is_present("delete"),
+            dry_run: sync_matches.is_present("dry-run"),
+            delta
+        };
+        let local = Path::new(sync_matches.value_of("local").unwrap());
+        let remote = session.remote_path(sync_matches.value_of("remote").unwrap());
+        or_exit(session.run(|transmitter, receiver, stream| commands::sync(transmitter, receiver, stream, local, &remote, &options)));
+        had_cmd = true;
+    }
+
+    if let Some(script) = matches.value_of("batch") {
+        let code = if script == "-" {
+            shell::run_batch(&mut session, "stdin", io::stdin().lock())
+        }
+        else {
+            shell::run_batch(&mut session, script, io::BufReader::new(or_exit(std::fs::File::open(script))))
+        };
+        commands::disconnect(&mut session.stream, &mut session.transmitter, &mut session.receiver).ok();
+        std::process::exit(code);
+    }
 
     if matches.is_present("shell") || !had_cmd {
-        shell::post_connection_shell(stream, transmitter, receiver);
+        shell::post_connection_shell(session);
     }
     else {
-        commands::disconnect(&mut stream, &mut transmitter, &mut receiver);
+        or_exit(commands::disconnect(&mut session.stream, &mut session.transmitter, &mut session.receiver));
     }
 }
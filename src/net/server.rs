@@ -1,31 +1,90 @@
-use std::net::{TcpListener, TcpStream, IpAddr, SocketAddr, Ipv4Addr};
-use std::io::{Read, Write};
-use crate::encoding::{FileReceiver, FileTransmitter};
+use std::net::{TcpListener, TcpStream, SocketAddr, Ipv4Addr, Ipv6Addr, Shutdown};
+use std::io::{self, IsTerminal, Read, Write};
+use crate::encoding::{self, FileReceiver, FileTransmitter};
 use crate::net::{self, Code, parse, create};
+use crate::throttle::{self, Limiter};
+use crate::compression::Codec;
+use crate::events::{self, Event, Hub};
+use crate::stats::{ServerStats, TransferStats};
+use crate::audit::{AuditLog, FileInfo, Record};
+use crate::metrics::Metrics;
+use crate::{listing, output, retry};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::thread;
+use std::time::{Duration, Instant};
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::{debug, error, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
+
+// There are no logins yet, so every connection is the same user
+const USER: &str = "anonymous";
+
+// Bandwidth caps, in bytes per second
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub global: Option<u64>,
+    pub connection: Option<u64>
+}
+
+// One socket for both IPv4 and IPv6 clients where the system allows it
+fn bind(port: u16) -> io::Result<TcpListener> {
+    let dual_stack = || -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.listen(128)?;
+        Ok(socket.into())
+    };
+
+    dual_stack().or_else(|e| {
+        warn!("IPv6 unavailable ({}), listening on IPv4 only", e);
+        TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+    })
+}
 
 // Listen for connections and create new thread on connection start
 pub struct ConnectionListener {
     _name: String,
-    listener: TcpListener
+    listener: TcpListener,
+    // The served directory, client paths never leave it
+    root: PathBuf,
+    limits: Limits,
+    global_limiter: Limiter,
+    hub: Hub,
+    audit: AuditLog,
+    metrics: Metrics
 }
 
 impl ConnectionListener {
-    pub fn new(name: &str, ip: IpAddr, port: u16) -> ConnectionListener {
-        let addr = SocketAddr::from((ip, port));
-        ConnectionListener{ _name: String::from(name), listener: TcpListener::bind(addr).unwrap() }
+    pub fn new(name: &str, listener: TcpListener, root: PathBuf, limits: Limits, audit: AuditLog, metrics: Metrics) -> ConnectionListener {
+        ConnectionListener{
+            _name: String::from(name),
+            listener,
+            root,
+            limits,
+            global_limiter: Limiter::new(limits.global),
+            hub: Hub::new(),
+            audit,
+            metrics
+        }
     }
 
     pub fn connection_loop(&self) {
         for stream in self.listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    let mut connection = Connection::new(stream);
+                    let mut connection = match Connection::new(stream, self.root.clone(), self.global_limiter.clone(), self.limits.connection, self.hub.clone(), self.audit.clone(), self.metrics.clone()) {
+                        Ok(connection) => connection,
+                        Err(e) => { warn!("Dropped a connection before it started: {}", e); continue; }
+                    };
                     //connection.handle();
                     thread::spawn(move || { connection.handle() });
                 }
                 Err(e) => {
-                    println!("Error accepting incoming connection: {}", e);
+                    warn!("Error accepting incoming connection: {}", e);
+                    self.metrics.error("accept");
                 }
             };
         }
@@ -35,80 +94,505 @@ impl ConnectionListener {
 // Server connection
 struct Connection {
     stream: TcpStream,
+    peer: SocketAddr,
+    root: PathBuf,
+    global_limiter: Limiter,
+    limiter: Limiter,
+    // Clients can lower their own cap but never raise it above this
+    max_rate: Option<u64>,
+    hub: Hub,
+    audit: AuditLog,
+    metrics: Metrics
+}
+
+// What a request counts as in the metrics and the audit log
+fn operation(code: Code) -> Option<&'static str> {
+    match code {
+        Code::Upload | Code::DeltaUpload | Code::Redirect => Some("upload"),
+        Code::Download | Code::DeltaDownload => Some("download"),
+        Code::Delete => Some("delete"),
+        Code::Rename => Some("rename"),
+        _ => None
+    }
 }
 
 impl Connection {
-    fn new(stream: TcpStream) -> Connection {
-        Connection { stream }
+    fn new(stream: TcpStream, root: PathBuf, global_limiter: Limiter, max_rate: Option<u64>, hub: Hub, audit: AuditLog, metrics: Metrics) -> io::Result<Connection> {
+        // IPv4 clients of a dual-stack socket show up as ::ffff:a.b.c.d
+        let peer = stream.peer_addr()?;
+        let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+        Ok(Connection { stream, peer, root, global_limiter, limiter: Limiter::new(max_rate), max_rate, hub, audit, metrics })
     }
 
     fn handle(&mut self) {
-        let addr = self.stream.local_addr().unwrap();
+        let span = info_span!("connection", peer = %self.peer, user = USER);
+        let _span = span.enter();
+        let _active = self.metrics.connection();
         let mut transmitter = FileTransmitter::new();
         let mut receiver = FileReceiver::new();
+        for limiter in [&self.global_limiter, &self.limiter].iter() {
+            transmitter.throttle((*limiter).clone());
+            receiver.throttle((*limiter).clone());
+        }
 
         let mut buf = [0; net::PACKET_SIZE ];
-        println!("[{}] Connection initiated", addr);
-        loop {
-            match self.stream.read(&mut buf) {
-                Ok(size) => {
-                    if size != 0 {
-                        let code = parse::packet(&buf);
+        info!("Connection initiated");
+        // Stops on Disconnect, or when the client goes away without one
+        while self.stream.read_exact(&mut buf).is_ok() {
+            let code = parse::packet(&buf);
 
-                        if code == net::Code::Disconnect {
-                            break;
-                        }
-                        self.handle_command(&mut transmitter, &mut receiver, code, buf, &addr);
+            if code == net::Code::Disconnect {
+                break;
+            }
+            if let Err(e) = self.handle_command(&mut transmitter, &mut receiver, code, buf) {
+                warn!("Connection lost: {}", e);
+                self.metrics.error("network");
+                break;
+            }
+        }
+        info!("Connection ended");
+    }
+
+    // Reading the file for its hash is only worth it when the audit log is kept
+    fn file_info(&self, path: &str) -> FileInfo {
+        if self.audit.enabled() { FileInfo::of(Path::new(path)) } else { FileInfo::default() }
+    }
+
+    // Every change to the served files and every download ends up here, in the log, the audit log and the metrics
+    fn finish(&self, action: &str, path: &str, to: Option<&str>, file: FileInfo, started: Instant, result: &io::Result<()>) {
+        self.metrics.finished(action, USER, Some(path), started.elapsed(), result.is_ok());
+        self.log(action, path, to, file, started, result);
+    }
+
+    // Turned down before it got to a file, so whatever path the client named stays out of the stats
+    fn refuse(&self, action: &str, path: &str, to: Option<&str>, started: Instant, error: io::Error) {
+        self.metrics.finished(action, USER, None, started.elapsed(), false);
+        self.log(action, path, to, FileInfo::default(), started, &Err(error));
+    }
+
+    fn log(&self, action: &str, path: &str, to: Option<&str>, file: FileInfo, started: Instant, result: &io::Result<()>) {
+        match result {
+            Ok(()) => info!(action, path, to, size = file.size, "{} finished", action),
+            Err(e) => warn!(action, path, to, "{} failed: {}", action, e)
+        }
+        self.audit.write(&Record {
+            time: chrono::Local::now().to_rfc3339(),
+            peer: self.peer.to_string(),
+            user: USER,
+            action,
+            path,
+            to,
+            file,
+            duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+            result: if result.is_ok() { "ok" } else { "error" },
+            error: result.as_ref().err().map(|e| e.to_string())
+        });
+    }
+
+    // Answers a request with an error message instead of the expected reply
+    fn reject(&mut self, message: &str) -> io::Result<()> {
+        let mut packet = net::Code::Stdout.packet();
+        for (i, b) in message.as_bytes().iter().enumerate() {
+            packet[i + 1] = *b;
+        }
+        self.stream.write_all(&packet)?;
+        self.stream.write_all(&net::Code::End.packet())
+    }
+
+    fn received(&self, path: &str, stats: &TransferStats) {
+        self.metrics.received(USER, &self.relative(path), stats);
+    }
+
+    fn sent(&self, path: &str, stats: &TransferStats) {
+        self.metrics.sent(USER, &self.relative(path), stats);
+    }
+
+    // Acknowledges an upload so the client knows it landed
+    fn finish_upload(&mut self, result: io::Result<TransferStats>, event: Event, path: &str, started: Instant) {
+        let result = match result {
+            Ok(stats) => {
+                debug!(path = %self.relative(path), "Received {}", stats);
+                self.received(path, &stats);
+                self.stream.write_all(&net::Code::Okay.packet()).ok();
+                self.hub.publish(event);
+                Ok(())
+            },
+            // The rest of the file may still be on its way, so the connection can't be trusted after this
+            Err(e) => {
+                if !retry::is_retryable(&e) {
+                    self.stream.write_all(&create::error(&e.to_string())).ok();
+                }
+                self.stream.shutdown(Shutdown::Both).ok();
+                Err(e)
+            }
+        };
+        let file = if result.is_ok() { self.file_info(path) } else { FileInfo::default() };
+        self.finish("upload", &self.relative(path), None, file, started, &result);
+    }
+
+    // The file data may already be on its way and can't be told apart from requests, so the connection ends here
+    fn refuse_upload(&mut self, name: &str, message: &str, started: Instant) {
+        self.stream.write_all(&create::error(message)).ok();
+        self.stream.shutdown(Shutdown::Both).ok();
+        self.refuse("upload", name, None, started, io::Error::other(message));
+    }
+
+    // Where an upload goes and the event it makes, None once it's been refused
+    fn upload_target(&mut self, name: &str, started: Instant) -> Option<(String, Event)> {
+        let path = match resolve(&self.root, name) {
+            Some(path) => path,
+            None => { self.refuse_upload(name, "Invalid path", started); return None; }
+        };
+        let event = self.write_event(&path);
+        if !fits(&event) {
+            self.refuse_upload(name, "Name too long", started);
+            return None;
+        }
+        Some((path, event))
+    }
+
+    // Create or Modify for a file about to be written
+    fn write_event(&self, path: &str) -> Event {
+        if Path::new(path).exists() { Event::Modify(self.relative(path)) } else { Event::Create(self.relative(path)) }
+    }
+
+    // The name a resolved path goes by in events, logs and stats
+    fn relative(&self, path: &str) -> String {
+        match Path::new(path).strip_prefix(&self.root).ok().and_then(|rest| rest.to_str()) {
+            Some(rest) => String::from(rest),
+            None => events::relative(path)
+        }
+    }
+
+    // Forwards events under dir until the client hangs up
+    fn watch(&mut self, events: Receiver<Event>, dir: &str) {
+        loop {
+            match events.recv_timeout(Duration::from_secs(1)) {
+                Ok(event) if !event.within(dir) => {},
+                Ok(event) => match create::event(&event) {
+                    Ok(packet) => if self.stream.write_all(&packet).is_err() {
+                        break;
+                    },
+                    Err(e) => warn!("Not sending {} event: {}", event.name(), e)
+                },
+                // A watching client only ever sends Disconnect, which is left for the main loop
+                Err(RecvTimeoutError::Timeout) => {
+                    if net::readable(&self.stream) {
+                        break;
                     }
                 },
-                Err(_e) => {}
+                Err(RecvTimeoutError::Disconnected) => break
             }
         }
-        println!("[{}] Connection ended", addr);
     }
 
-    fn handle_command(&mut self, transmitter: &mut FileTransmitter, receiver: &mut FileReceiver, command: Code, packet: [u8; net::PACKET_SIZE], addr: &std::net::SocketAddr) -> [u8; net::PACKET_SIZE] {
-        //println!("[{}] Received code {:?}", addr, command);
+    fn handle_command(&mut self, transmitter: &mut FileTransmitter, receiver: &mut FileReceiver, command: Code, packet: [u8; net::PACKET_SIZE]) -> io::Result<[u8; net::PACKET_SIZE]> {
+        debug!("Received code {:?}", command);
+        let started = Instant::now();
+        if let Some(op) = operation(command) {
+            self.metrics.started(op);
+        }
         match command {
             Code::Upload => {
                 let (name, id) = parse::upload(&packet);
-                println!("[{}] Receiving upload: {}", addr, name);
-                let stats = receiver.get_file(&name, id, &mut self.stream);
-                println!("[{}]\t{}: {}", addr, name, stats);
-                net::Code::Okay.packet()
+                info!("Receiving upload: {}", name);
+                let (path, event) = match self.upload_target(&name, started) {
+                    Some(target) => target,
+                    None => return Ok(net::Code::Error.packet())
+                };
+                let result = receiver.get_file(&path, id, &mut self.stream);
+                self.finish_upload(result, event, &path, started);
+                Ok(net::Code::Okay.packet())
+            },
+            Code::DeltaUpload => {
+                let name = parse::delta_upload(&packet);
+                info!("Receiving delta upload: {}", name);
+                let (path, event) = match self.upload_target(&name, started) {
+                    Some(target) => target,
+                    None => return Ok(net::Code::Error.packet())
+                };
+                let result = receiver.send_signature(&path, &mut self.stream)
+                    .and_then(|_| receiver.get_file(&path, 0, &mut self.stream));
+                self.finish_upload(result, event, &path, started);
+                Ok(net::Code::Okay.packet())
             },
             Code::Delete => {
                 let arg = parse::delete(packet);
-                let _res = receiver.delete_file(&mut self.stream, &arg);
-                net::Code::Okay.packet()
+                match resolve(&self.root, &arg) {
+                    Some(path) if !fits(&Event::Delete(self.relative(&path))) => {
+                        self.refuse("delete", &arg, None, started, io::Error::other("Name too long"));
+                        self.reject("Name too long")?;
+                    },
+                    Some(path) => {
+                        let file = self.file_info(&path);
+                        let result = receiver.delete_file(&mut self.stream, &path);
+                        if result.is_ok() {
+                            self.hub.publish(Event::Delete(self.relative(&path)));
+                        }
+                        self.finish("delete", &self.relative(&path), None, file, started, &result);
+                    },
+                    None => {
+                        self.refuse("delete", &arg, None, started, io::Error::other("Invalid path"));
+                        self.reject("Invalid path")?;
+                    }
+                }
+                Ok(net::Code::Okay.packet())
+            },
+            Code::Rename => {
+                let (from, to) = parse::rename(&packet);
+                match (resolve(&self.root, &from), resolve(&self.root, &to)) {
+                    (Some(resolved), Some(target)) if !fits(&Event::Rename(self.relative(&resolved), self.relative(&target))) => {
+                        self.refuse("rename", &from, Some(&to), started, io::Error::other("Names too long"));
+                        self.reject("Names too long")?;
+                    },
+                    (Some(from), Some(to)) => {
+                        let result = std::fs::rename(&from, &to);
+                        if result.is_ok() {
+                            self.hub.publish(Event::Rename(self.relative(&from), self.relative(&to)));
+                        }
+                        let file = self.file_info(&to);
+                        self.finish("rename", &self.relative(&from), Some(&self.relative(&to)), file, started, &result);
+                        // The rename happened whether or not the client is still there to hear it
+                        match result {
+                            Ok(()) => self.stream.write_all(&net::Code::End.packet())?,
+                            Err(_) => self.reject("Unable to rename file")?
+                        }
+                    },
+                    _ => {
+                        self.refuse("rename", &from, Some(&to), started, io::Error::other("Invalid path"));
+                        self.reject("Invalid path")?;
+                    }
+                }
+                Ok(net::Code::Okay.packet())
             },
             Code::Dir => {
-                let _arg = parse::dir(packet);
-                let _res = transmitter.dir("./", &mut self.stream);
-                net::Code::Okay.packet()
+                match resolve(&self.root, &parse::dir(packet)).filter(|path| Path::new(path).is_dir()) {
+                    Some(path) => transmitter.dir(&path, &mut self.stream),
+                    None => self.reject("No such directory")?
+                }
+                Ok(net::Code::Okay.packet())
             },
             Code::Redirect => {
                 let (port, filename) = parse::redirect(packet);
-                let stats = receiver.get_file(&filename, port, &mut self.stream);
-                println!("[{}] {}", addr, stats);
-                net::Code::Okay.packet()
+                let (path, event) = match self.upload_target(&filename, started) {
+                    Some(target) => target,
+                    None => return Ok(net::Code::Error.packet())
+                };
+                let result = receiver.get_file(&path, port, &mut self.stream);
+                self.finish_upload(result, event, &path, started);
+                Ok(net::Code::Okay.packet())
             },
             Code::Download => {
-                let path = parse::download(&packet);
-                self.stream.write_all(&create::redirect(&path, 0)).expect("Network error");
-                let _stats = transmitter.host_file(&path, &mut self.stream);
-                net::Code::Okay.packet()
+                let (path, codec, offset, mtime) = parse::download(&packet);
+                let local = match resolve(&self.root, &path).filter(|p| Path::new(p).is_file()) {
+                    Some(local) => local,
+                    None => {
+                        self.refuse("download", &path, None, started, io::Error::other("No such file"));
+                        self.reject("No such file")?;
+                        return Ok(net::Code::Error.packet());
+                    }
+                };
+                // The client's partial copy is only good if the file hasn't changed since
+                let current = std::fs::metadata(&local).and_then(|m| m.modified()).map(listing::to_nanos).unwrap_or(0);
+                let offset = if mtime == current { offset } else { 0 };
+
+                let codec = Codec::from_u8(codec).unwrap_or(Codec::None).negotiate(Codec::supported());
+                transmitter.set_codec(codec);
+                self.stream.write_all(&create::redirect(&path, 0))?;
+                let result = transmitter.host_file_from(&local, offset, &mut self.stream).map(|stats| self.sent(&local, &stats));
+                let file = self.file_info(&local);
+                self.finish("download", &self.relative(&local), None, file, started, &result);
+                Ok(net::Code::Okay.packet())
+            },
+            Code::DeltaDownload => {
+                let (path, codec, _offset, _mtime) = parse::download(&packet);
+                let signature = match transmitter.read_signature(&mut self.stream) {
+                    Ok(signature) => signature,
+                    // What's left of a signature that didn't add up can't be told apart from requests
+                    Err(e) => {
+                        self.stream.shutdown(Shutdown::Both).ok();
+                        self.refuse("download", &path, None, started, e);
+                        return Ok(net::Code::Error.packet());
+                    }
+                };
+                let local = match resolve(&self.root, &path).filter(|p| Path::new(p).is_file()) {
+                    Some(local) => local,
+                    None => {
+                        self.refuse("download", &path, None, started, io::Error::other("No such file"));
+                        self.reject("No such file")?;
+                        return Ok(net::Code::Error.packet());
+                    }
+                };
+                let codec = Codec::from_u8(codec).unwrap_or(Codec::None).negotiate(Codec::supported());
+                transmitter.set_codec(codec);
+                self.stream.write_all(&create::redirect(&path, 0))?;
+                let result = transmitter.host_delta(&local, &signature, &mut self.stream).map(|stats| self.sent(&local, &stats));
+                let file = self.file_info(&local);
+                self.finish("download", &self.relative(&local), None, file, started, &result);
+                Ok(net::Code::Okay.packet())
+            },
+            Code::List => {
+                let (path, flags) = parse::list_request(&packet);
+                // Paths outside the served directory list as empty
+                match resolve(&self.root, &path) {
+                    Some(path) => transmitter.send_listing(&path, flags, &mut self.stream)?,
+                    None => self.stream.write_all(&create::list(0, 0))?
+                }
+                Ok(net::Code::Okay.packet())
+            },
+            Code::Watch => {
+                let dir = match resolve(&self.root, &parse::watch(&packet)) {
+                    Some(dir) => self.relative(&dir),
+                    None => { self.reject("Invalid path")?; return Ok(net::Code::Error.packet()); }
+                };
+                info!("Watching {}", if dir.is_empty() { "everything" } else { &dir });
+                let events = self.hub.subscribe();
+                self.watch(events, &dir);
+                info!("Stopped watching");
+                Ok(net::Code::Okay.packet())
             },
-            _ => { println!("[{}] Unknown command!", addr); net::Code::Error.packet() }
+            Code::Resume => {
+                let name = parse::resume_request(&packet);
+                let (len, mtime) = match resolve(&self.root, &name) {
+                    Some(path) => {
+                        let part = encoding::part_path(Path::new(&path));
+                        let mtime = std::fs::metadata(&part).and_then(|m| m.modified()).map(listing::to_nanos).unwrap_or(0);
+                        (encoding::partial_len(&part), mtime)
+                    },
+                    None => (0, 0)
+                };
+                self.stream.write_all(&create::resume(len, mtime))?;
+                Ok(net::Code::Okay.packet())
+            },
+            Code::Stats => {
+                // Only someone on the server's own machine counts as an administrator, until there are logins
+                // This is a stopgap: behind a reverse proxy on the same machine every client looks local
+                if !self.peer.ip().is_loopback() {
+                    warn!("Refused server statistics to a remote client");
+                    self.reject("Only administrators can see server statistics")?;
+                    return Ok(net::Code::Error.packet());
+                }
+                let json = serde_json::to_vec(&self.metrics.summary()).expect("Server statistics always serialize");
+                self.stream.write_all(&create::stats(json.len() as u64))?;
+                self.stream.write_all(&json)?;
+                Ok(net::Code::Okay.packet())
+            },
+            Code::Hello => {
+                let codecs = parse::hello(packet);
+                debug!("Client supports codecs {:#04x}", codecs);
+                self.stream.write_all(&create::hello(Codec::supported()))?;
+                Ok(net::Code::Okay.packet())
+            },
+            Code::Limit => {
+                let rate = match (parse::limit(packet), self.max_rate) {
+                    (Some(rate), Some(max)) => Some(rate.min(max)),
+                    (None, max) => max,
+                    (rate, None) => rate
+                };
+                info!("Connection limit set to {}", throttle::format_rate(rate));
+                self.limiter.set_rate(rate);
+                Ok(net::Code::Okay.packet())
+            },
+            _ => {
+                warn!("Unknown command {:?}", command);
+                self.metrics.error("protocol");
+                Ok(net::Code::Error.packet())
+            }
+        }
+    }
+}
+
+// Watching clients hear of every change, so names too long for an event are refused up front
+fn fits(event: &Event) -> bool {
+    create::event(event).is_ok()
+}
+
+// Keeps client supplied paths inside the served directory
+fn resolve(root: &Path, path: &str) -> Option<String> {
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {},
+            _ => return None
         }
     }
+    resolved.to_str().map(String::from)
 }
 
 // Start server
-pub fn start_server(_matches: &clap::ArgMatches) {
-    let ip = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
-    let port = 3219;
-    let listener = ConnectionListener::new("TheBlackPearl", ip, port);
+pub fn start_server(matches: &clap::ArgMatches) {
+    // RUST_LOG takes over when --log-level is left out
+    let filter = match (matches.occurrences_of("log-level"), EnvFilter::try_from_default_env()) {
+        (0, Ok(filter)) => filter,
+        _ => EnvFilter::try_new(matches.value_of("log-level").unwrap()).expect("Please provide a valid log level, e.g. info or debug")
+    };
+    let logger = tracing_subscriber::fmt().with_env_filter(filter);
+    if matches.is_present("log-json") {
+        logger.json().init();
+    }
+    else {
+        logger.with_ansi(io::stdout().is_terminal()).init();
+    }
+    output::set_mode(output::Mode::Log);
+
+    let port: u16 = matches.value_of("port").unwrap_or("3219").parse().expect("Please provide a valid port");
+
+    let limit = |name| matches.value_of(name)
+        .map(|rate| throttle::parse_rate(rate).expect("Please provide a valid rate, e.g. 10M"))
+        .unwrap_or(None);
+    let limits = Limits { global: limit("limit"), connection: limit("conn-limit") };
+    info!("Bandwidth limit: {} total, {} per connection",
+          throttle::format_rate(limits.global), throttle::format_rate(limits.connection));
+
+    let audit = match matches.value_of("audit-log") {
+        Some(path) => AuditLog::open(Path::new(path)).unwrap_or_else(|e| {
+            error!("Unable to open the audit log {}: {}", path, e);
+            std::process::exit(1);
+        }),
+        None => AuditLog::default()
+    };
+
+    // Saved every minute, so a restart loses at most the last one
+    let stats = match matches.value_of("stats-file") {
+        Some(path) => {
+            let stats = ServerStats::load(Path::new(path)).unwrap_or_else(|e| {
+                error!("Unable to load the statistics in {}: {}", path, e);
+                std::process::exit(1);
+            });
+            let saved = stats.clone();
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(60));
+                if let Err(e) = saved.save() {
+                    warn!("Unable to save the statistics: {}", e);
+                }
+            });
+            stats
+        },
+        None => ServerStats::new()
+    };
+
+    let metrics = Metrics::new(stats);
+    if let Some(addr) = matches.value_of("metrics") {
+        // A bare port stays on this machine
+        let addr = match addr.parse::<u16>() {
+            Ok(port) => SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            Err(_) => addr.parse().expect("Please provide a port or address:port for the metrics")
+        };
+        if let Err(e) = metrics.serve(addr) {
+            error!("Unable to serve metrics on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+        info!("Metrics on http://{}/metrics", addr);
+    }
+
+    let listener = bind(port).unwrap_or_else(|e| {
+        error!("Unable to listen on port {}: {}", port, e);
+        std::process::exit(1);
+    });
+    // Port 0 leaves the choice to the system
+    info!("Listening on port {}", listener.local_addr().map(|addr| addr.port()).unwrap_or(port));
+    let listener = ConnectionListener::new("TheBlackPearl", listener, PathBuf::from("."), limits, audit, metrics);
 
     listener.connection_loop();
 }
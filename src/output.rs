@@ -0,0 +1,128 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU8, Ordering};
+use serde::Serialize;
+use serde_json::{json, Value};
+use crate::stats::{self, TransferStats};
+
+// Where results and chatter go, set once at startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Text = 0,
+    // Stdout only carries JSON lines, everything meant for people goes to stderr
+    Json = 1,
+    // The server, where chatter becomes debug logging
+    Log = 2
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Text as u8);
+
+pub fn set_mode(mode: Mode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Mode::Json,
+        2 => Mode::Log,
+        _ => Mode::Text
+    }
+}
+
+pub fn json() -> bool {
+    mode() == Mode::Json
+}
+
+// Progress and other chatter that scripts shouldn't have to parse
+#[macro_export]
+macro_rules! note {
+    ($($arg:tt)*) => {
+        $crate::output::note(format_args!($($arg)*))
+    };
+}
+
+pub fn note(args: fmt::Arguments) {
+    match mode() {
+        Mode::Text => println!("{}", args),
+        Mode::Json => eprintln!("{}", args),
+        Mode::Log => tracing::debug!("{}", args)
+    }
+}
+
+// The outcome of a command, as text or as one JSON object
+pub fn result(text: impl fmt::Display, value: Value) {
+    if json() {
+        println!("{}", value);
+    }
+    else {
+        println!("{}", text);
+    }
+}
+
+// Outcomes people are only told about when something goes wrong
+pub fn record(value: Value) {
+    if json() {
+        println!("{}", value);
+    }
+}
+
+pub fn error(message: impl fmt::Display) {
+    if json() {
+        println!("{}", json!({"type": "error", "message": message.to_string()}));
+    }
+    else {
+        colour::red_ln!("{}", message);
+    }
+}
+
+// A step of a sync, as its line in the plan or its JSON with the action and path
+pub fn action<T: fmt::Display + Serialize>(action: &T) {
+    let mut value = serde_json::to_value(action).unwrap_or(Value::Null);
+    value["type"] = json!("action");
+    result(action, value);
+}
+
+pub fn transfer(kind: &str, local: &str, remote: &str, stats: &TransferStats) {
+    result(stats, json!({"type": kind, "local": local, "remote": remote, "stats": stats}));
+    log_transfer(kind, local, remote, stats);
+}
+
+// Every transfer, finished or not, appended as CSV or JSON lines for looking at later
+struct TransferLog {
+    file: File,
+    csv: bool
+}
+
+static TRANSFER_LOG: Mutex<Option<TransferLog>> = Mutex::new(None);
+
+// CSV when the name ends in .csv, with a header if the file is new
+pub fn open_transfer_log(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let csv = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("csv"));
+    if csv && file.metadata()?.len() == 0 {
+        writeln!(file, "kind,local,remote,{}", TransferStats::CSV_HEADER)?;
+    }
+    *TRANSFER_LOG.lock().unwrap() = Some(TransferLog { file, csv });
+    Ok(())
+}
+
+pub fn log_transfer(kind: &str, local: &str, remote: &str, stats: &TransferStats) {
+    crate::history::transfer(kind, local, remote, stats);
+    let mut log = TRANSFER_LOG.lock().unwrap();
+    let log = match log.as_mut() {
+        Some(log) => log,
+        None => return
+    };
+    let line = if log.csv {
+        format!("{},{},{},{}\n", kind, stats::csv_field(local), stats::csv_field(remote), stats.csv_row())
+    }
+    else {
+        format!("{}\n", json!({"kind": kind, "local": local, "remote": remote, "stats": stats}))
+    };
+    if let Err(e) = log.file.write_all(line.as_bytes()) {
+        eprintln!("Unable to write to the transfer log: {}", e);
+    }
+}
@@ -0,0 +1,98 @@
+use std::io;
+use std::time::Duration;
+use rand::Rng;
+
+const BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // Attempts after the first one, 0 never retries
+    pub retries: u32,
+    // How long a connect, read or write may block
+    pub timeout: Option<Duration>
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy { retries: 3, timeout: Some(Duration::from_secs(30)) }
+    }
+}
+
+// Failures a fresh connection might fix, as opposed to bad files or bad requests
+pub fn is_retryable(e: &io::Error) -> bool {
+    matches!(e.kind(),
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::HostUnreachable
+        | io::ErrorKind::NetworkUnreachable
+        | io::ErrorKind::NetworkDown)
+}
+
+// Exponential backoff with jitter, so clients dropped together don't return together
+#[derive(Debug)]
+pub struct Backoff {
+    retries: u32,
+    attempt: u32
+}
+
+impl Backoff {
+    pub fn new(policy: &RetryPolicy) -> Backoff {
+        Backoff { retries: policy.retries, attempt: 0 }
+    }
+
+    // Delay before the next attempt, None once the retries are used up
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempt >= self.retries {
+            return None;
+        }
+
+        let ceiling = BASE_DELAY.saturating_mul(1 << self.attempt.min(16)).min(MAX_DELAY);
+        self.attempt += 1;
+
+        // Half fixed, half random
+        let half = ceiling.as_millis() as u64 / 2;
+        Some(Duration::from_millis(half + rand::thread_rng().gen_range(0..=half)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_within_bounds() {
+        let mut backoff = Backoff::new(&RetryPolicy { retries: 20, timeout: None });
+        let mut ceiling = BASE_DELAY;
+        while let Some(delay) = backoff.next_delay() {
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?} outside {:?}", delay, ceiling);
+            ceiling = (ceiling * 2).min(MAX_DELAY);
+        }
+        assert_eq!(ceiling, MAX_DELAY);
+    }
+
+    #[test]
+    fn stops_after_the_retries() {
+        let mut backoff = Backoff::new(&RetryPolicy { retries: 2, timeout: None });
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(Backoff::new(&RetryPolicy { retries: 0, timeout: None }).next_delay(), None);
+    }
+
+    #[test]
+    fn only_network_failures_are_retried() {
+        assert!(is_retryable(&io::Error::from(io::ErrorKind::ConnectionReset)));
+        assert!(is_retryable(&io::Error::from(io::ErrorKind::UnexpectedEof)));
+        assert!(!is_retryable(&io::Error::from(io::ErrorKind::NotFound)));
+        assert!(!is_retryable(&io::Error::from(io::ErrorKind::InvalidData)));
+    }
+}
@@ -1,112 +1,415 @@
-use std::time::Instant;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    InProgress,
+    Completed,
+    Failed
+}
+
+// One transfer from start to finish, rates in bytes per second
+#[derive(Debug, Serialize)]
 pub struct TransferStats {
-    elapsed: f32,
+    started: DateTime<Utc>,
+    ended: Option<DateTime<Utc>>,
+    #[serde(rename = "elapsed_secs", serialize_with = "as_secs")]
+    elapsed: Duration,
     bytes: usize,
-    instant: Instant 
+    // Bytes actually sent over the socket, headers and compression included
+    wire_bytes: usize,
+    average_rate: f64,
+    peak_rate: f64,
+    // Reconnects it took, the bytes only count the attempt that finished
+    retries: u32,
+    outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip)]
+    instant: Instant
+}
+
+fn as_secs<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
 }
 
 impl fmt::Display for TransferStats {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        //Use Kb/s and stuff
-        let bits = self.bytes * 8;
+        use crate::encoding::{format_bytes, format_duration};
 
-        let (rate, rate_name) = 
-        if bits > 1000000000 {
-            (bits as f32 / 1000000000.0, "Gb")
+        if self.outcome == Outcome::Failed {
+            write!(f, "Failed after {}", format_duration(self.elapsed))?;
+        }
+        else {
+            write!(f, "{} in {}, {}/s average, {}/s peak, {} on the wire", format_bytes(self.bytes), format_duration(self.elapsed),
+                format_bytes(self.average_rate as usize), format_bytes(self.peak_rate as usize), format_bytes(self.wire_bytes))?;
         }
-        else if bits > 1000000 {
-            (bits as f32 / 1000000.0, "Mb")
+        match self.retries {
+            0 => {},
+            1 => write!(f, ", 1 retry")?,
+            retries => write!(f, ", {} retries", retries)?
         }
-        else if bits > 1000 {
-            (bits as f32 / 1000.0, "Kb")
+        if let Some(error) = self.error.as_ref() {
+            write!(f, ": {}", error)?;
         }
-        else {
-            (bits as f32, "b")
-        };
+        Ok(())
+    }
+}
+
+impl Default for TransferStats {
+    fn default() -> TransferStats {
+        TransferStats::new()
+    }
+}
 
-        write!(f, "{} {}/s", rate, rate_name)
+// Commas and quotes in a CSV field are quoted
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    }
+    else {
+        field.to_string()
     }
 }
 
 impl TransferStats {
+    pub const CSV_HEADER: &'static str = "started,ended,elapsed_secs,bytes,wire_bytes,average_rate,peak_rate,retries,outcome,error";
+
     pub fn new() -> TransferStats {
-        TransferStats { elapsed: 0.0, bytes: 0, instant: Instant::now() }
+        TransferStats {
+            started: Utc::now(),
+            ended: None,
+            elapsed: Duration::from_secs(0),
+            bytes: 0,
+            wire_bytes: 0,
+            average_rate: 0.0,
+            peak_rate: 0.0,
+            retries: 0,
+            outcome: Outcome::InProgress,
+            error: None,
+            instant: Instant::now()
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn wire_bytes(&self) -> usize {
+        self.wire_bytes
     }
 
-    //pub fn start(&mut self) {
-    //    self.instant = Instant::now();
-    //}
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn average_rate(&self) -> f64 {
+        self.average_rate
+    }
 
-    pub fn stop(&mut self, bytes: usize) {
-        self.elapsed = self.instant.elapsed().as_nanos() as f32 / 1000.0;
+    pub fn peak_rate(&self) -> f64 {
+        self.peak_rate
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn outcome(&self) -> Outcome {
+        self.outcome
+    }
+
+    pub fn started(&self) -> DateTime<Utc> {
+        self.started
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    // The peak comes from the live estimate, short transfers finish before there is one
+    pub fn stop(&mut self, bytes: usize, wire_bytes: usize, peak_rate: f64) {
+        self.finish(Outcome::Completed);
         self.bytes = bytes;
+        self.wire_bytes = wire_bytes;
+        let secs = self.elapsed.as_secs_f64();
+        self.average_rate = if secs > 0.0 { bytes as f64 / secs } else { 0.0 };
+        self.peak_rate = peak_rate.max(self.average_rate);
+    }
+
+    pub fn fail(&mut self, error: &io::Error) {
+        self.finish(Outcome::Failed);
+        self.error = Some(error.to_string());
+    }
+
+    fn finish(&mut self, outcome: Outcome) {
+        self.elapsed = self.instant.elapsed();
+        self.ended = Some(Utc::now());
+        self.outcome = outcome;
+    }
+
+    // Fields in the order of CSV_HEADER
+    pub fn csv_row(&self) -> String {
+        let time = |time: &DateTime<Utc>| time.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        let outcome = serde_json::to_value(self.outcome).ok().and_then(|v| v.as_str().map(String::from)).unwrap_or_default();
+        format!("{},{},{},{},{},{:.0},{:.0},{},{},{}", time(&self.started), self.ended.as_ref().map(time).unwrap_or_default(),
+            self.elapsed.as_secs_f64(), self.bytes, self.wire_bytes, self.average_rate, self.peak_rate, self.retries,
+            outcome, csv_field(self.error.as_deref().unwrap_or("")))
     }
 }
 
+// How far back the rate is measured, and how often it's sampled within that
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+// Weight of the newest window rate against the running estimate
+const SMOOTHING: f64 = 0.3;
+
+// Live rate and time remaining of one transfer
 #[derive(Debug)]
 pub struct RealtimeStats {
     instant: Instant,
     current_bytes: usize,
     size: usize,
-    measures: Vec<(u64, usize)>
+    // Time since the start and bytes done so far, spanning at least the window once it's been that long
+    samples: VecDeque<(Duration, usize)>,
+    // Bytes per second
+    rate: Option<f64>,
+    peak: f64
 }
 
 impl RealtimeStats {
-    pub fn new() -> RealtimeStats {
-        RealtimeStats { instant: Instant::now(), current_bytes: 0, size: 0, measures: vec![(0, 0)] }
+    // A resumed transfer starts out with offset bytes done, which don't count towards the rate
+    pub fn new(size: usize, offset: usize) -> RealtimeStats {
+        let mut samples = VecDeque::new();
+        samples.push_back((Duration::from_secs(0), offset));
+        RealtimeStats { instant: Instant::now(), current_bytes: offset, size, samples, rate: None, peak: 0.0 }
     }
 
-    pub fn set_size(&mut self, size: usize) {
-        if self.size == 0 {
-            self.size = size
+    // True when the estimate changed
+    pub fn add_bytes(&mut self, bytes: usize) -> bool {
+        self.current_bytes += bytes;
+        let now = self.instant.elapsed();
+        if self.samples.back().is_some_and(|(last, _)| now - *last < SAMPLE_INTERVAL) {
+            return false;
         }
+
+        self.samples.push_back((now, self.current_bytes));
+        while self.samples.len() > 2 && now - self.samples[1].0 >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let (start, start_bytes) = self.samples[0];
+        let window = (now - start).as_secs_f64();
+        if window <= 0.0 {
+            return false;
+        }
+        let rate = (self.current_bytes - start_bytes) as f64 / window;
+        let rate = match self.rate {
+            Some(old) => SMOOTHING * rate + (1.0 - SMOOTHING) * old,
+            None => rate
+        };
+        self.rate = Some(rate);
+        self.peak = self.peak.max(rate);
+        true
     }
-    pub fn add_bytes(&mut self, bytes: usize) {
-        self.measures.push((self.instant.elapsed().as_nanos() as u64, self.current_bytes));
-        self.current_bytes += bytes;
+
+    pub fn current_bytes(&self) -> usize {
+        self.current_bytes
     }
 
-    // Return speed in bits
-    pub fn _get_speed(&mut self) -> (usize, u64) {
-        // Look at past second
-        const _SECOND: u64 = 1000000000;
+    // Bytes per second, 0 until there's been time to measure
+    pub fn rate(&self) -> f64 {
+        self.rate.unwrap_or(0.0)
+    }
 
-        let (last_time, last_bytes) = match self.measures.last() {
-           Some((last_time, last_bytes)) => (*last_time, *last_bytes),
-           None => (0, 0)
-        }; 
+    // Highest smoothed rate so far
+    pub fn peak(&self) -> f64 {
+        self.peak
+    }
 
-        if (last_time, last_bytes) != (0, 0) {
-            let threshold = if last_time > _SECOND { last_time - _SECOND } else { 0 };
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate.filter(|rate| *rate > 0.0)?;
+        let remaining = self.size.saturating_sub(self.current_bytes);
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
 
-            let mut c = 0;
-            for m in self.measures.iter() {
-                if m.0 < threshold {
-                    c += 1;
-                }
-                else {
-                    break;
-                }
-            }
+// How many minutes of throughput the server remembers
+const THROUGHPUT_MINUTES: usize = 60;
+// How many paths the server keeps totals for
+const MAX_PATHS: usize = 1000;
 
-            for _i in 0..c {
-                self.measures.remove(0);
-            }
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Totals {
+    pub uploads: u64,
+    pub downloads: u64,
+    pub deletes: u64,
+    pub renames: u64,
+    pub failures: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64
+}
 
-            if let Some((start_time, start_bytes)) = self.measures.first() {
-                (last_bytes - start_bytes, last_time - start_time)
+impl Totals {
+    fn finished(&mut self, op: &str, ok: bool) {
+        if !ok {
+            self.failures += 1;
+            return;
+        }
+        match op {
+            "upload" => self.uploads += 1,
+            "download" => self.downloads += 1,
+            "delete" => self.deletes += 1,
+            "rename" => self.renames += 1,
+            _ => {}
+        }
+    }
+}
+
+// File bytes moved during one minute
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Sample {
+    // Start of the minute in seconds since the epoch
+    pub minute: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64
+}
+
+// Everything the server has done since it was first started with this stats file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Summary {
+    pub since: String,
+    pub connections: u64,
+    pub active: u64,
+    pub peak_connections: u64,
+    pub totals: Totals,
+    pub users: BTreeMap<String, Totals>,
+    pub paths: BTreeMap<String, Totals>,
+    pub throughput: VecDeque<Sample>
+}
+
+impl Summary {
+    fn sample(&mut self) -> &mut Sample {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let minute = now - now % 60;
+        if self.throughput.back().is_none_or(|sample| sample.minute != minute) {
+            self.throughput.push_back(Sample { minute, ..Sample::default() });
+            if self.throughput.len() > THROUGHPUT_MINUTES {
+                self.throughput.pop_front();
             }
-            else {
-               (self.current_bytes - last_bytes, _SECOND)
+        }
+        self.throughput.back_mut().unwrap()
+    }
+
+    // Only the busiest paths are kept, the one that moved the fewest bytes makes room for a new one
+    fn path(&mut self, path: &str) -> &mut Totals {
+        if !self.paths.contains_key(path) && self.paths.len() >= MAX_PATHS {
+            let quietest = self.paths.iter().min_by_key(|(_, totals)| totals.bytes_in + totals.bytes_out).map(|(path, _)| path.clone());
+            if let Some(quietest) = quietest {
+                self.paths.remove(&quietest);
             }
+        }
+        self.paths.entry(String::from(path)).or_default()
+    }
+}
+
+// Server-wide totals kept in memory, and in a file when one is given
+#[derive(Clone, Default)]
+pub struct ServerStats {
+    summary: Arc<Mutex<Summary>>,
+    path: Option<PathBuf>
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        let summary = Summary { since: chrono::Local::now().to_rfc3339(), ..Summary::default() };
+        ServerStats { summary: Arc::new(Mutex::new(summary)), path: None }
+    }
 
+    // Carries on from the totals saved in path, if there are any yet
+    pub fn load(path: &Path) -> io::Result<ServerStats> {
+        let mut stats = ServerStats::new();
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                let mut summary: Summary = serde_json::from_str(&text)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+                summary.active = 0;
+                stats.summary = Arc::new(Mutex::new(summary));
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e)
         }
-        else {
-            println!("nope");
-           (0, _SECOND)
+        stats.path = Some(PathBuf::from(path));
+        Ok(stats)
+    }
+
+    // Written next to the file and renamed over it, so a crash never leaves half of it
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        let json = serde_json::to_string_pretty(&self.snapshot()).map_err(io::Error::other)?;
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, json)?;
+        fs::rename(&temporary, path)
+    }
+
+    pub fn connected(&self) {
+        let mut summary = self.summary.lock().unwrap();
+        summary.connections += 1;
+        summary.active += 1;
+        summary.peak_connections = summary.peak_connections.max(summary.active);
+    }
+
+    pub fn disconnected(&self) {
+        let mut summary = self.summary.lock().unwrap();
+        summary.active = summary.active.saturating_sub(1);
+    }
+
+    // Requests turned down before they touched a file have no path
+    pub fn finished(&self, user: &str, path: Option<&str>, op: &str, ok: bool) {
+        let mut summary = self.summary.lock().unwrap();
+        summary.totals.finished(op, ok);
+        summary.users.entry(String::from(user)).or_default().finished(op, ok);
+        if let Some(path) = path {
+            summary.path(path).finished(op, ok);
         }
     }
+
+    pub fn received(&self, user: &str, path: &str, stats: &TransferStats) {
+        let bytes = stats.bytes() as u64;
+        let mut summary = self.summary.lock().unwrap();
+        summary.totals.bytes_in += bytes;
+        summary.users.entry(String::from(user)).or_default().bytes_in += bytes;
+        summary.path(path).bytes_in += bytes;
+        summary.sample().bytes_in += bytes;
+    }
+
+    pub fn sent(&self, user: &str, path: &str, stats: &TransferStats) {
+        let bytes = stats.bytes() as u64;
+        let mut summary = self.summary.lock().unwrap();
+        summary.totals.bytes_out += bytes;
+        summary.users.entry(String::from(user)).or_default().bytes_out += bytes;
+        summary.path(path).bytes_out += bytes;
+        summary.sample().bytes_out += bytes;
+    }
+
+    pub fn snapshot(&self) -> Summary {
+        self.summary.lock().unwrap().clone()
+    }
 }
@@ -0,0 +1,332 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::listing::{self, Entry};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // Local directory is the source
+    Push,
+    // Remote directory is the source
+    Pull
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compare {
+    SizeAndTime,
+    Checksum
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", content = "path", rename_all = "snake_case")]
+pub enum Action {
+    Create(String),
+    Update(String),
+    Delete(String),
+    DeleteDir(String)
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Create(path) => write!(f, "copy    {} (new)", path),
+            Action::Update(path) => write!(f, "copy    {} (changed)", path),
+            Action::Delete(path) => write!(f, "delete  {}", path),
+            Action::DeleteDir(path) => write!(f, "delete  {}/", path)
+        }
+    }
+}
+
+// Filesystems disagree on sub-second precision, whole seconds are enough
+fn same_time(a: u64, b: u64) -> bool {
+    a / 1000000000 == b / 1000000000
+}
+
+pub fn changed(source: &Entry, dest: &Entry, compare: Compare) -> bool {
+    if source.is_dir != dest.is_dir || source.size != dest.size {
+        return true;
+    }
+
+    match compare {
+        Compare::SizeAndTime => !same_time(source.mtime, dest.mtime),
+        Compare::Checksum => source.hash != dest.hash
+    }
+}
+
+// What it takes to make dest a mirror of source
+pub fn plan(source: &[Entry], dest: &[Entry], compare: Compare, delete: bool) -> Vec<Action> {
+    let dest_map: HashMap<&str, &Entry> = dest.iter().map(|e| (e.path.as_str(), e)).collect();
+    let source_map: HashMap<&str, &Entry> = source.iter().map(|e| (e.path.as_str(), e)).collect();
+    let mut actions = Vec::new();
+
+    for entry in source.iter().filter(|e| !e.is_dir) {
+        match dest_map.get(entry.path.as_str()) {
+            None => actions.push(Action::Create(entry.path.clone())),
+            Some(existing) if changed(entry, existing, compare) => actions.push(Action::Update(entry.path.clone())),
+            Some(_) => {}
+        }
+    }
+
+    if delete {
+        // Children sort after their parent, so reversed order empties directories first
+        for entry in dest.iter().rev() {
+            match source_map.get(entry.path.as_str()) {
+                Some(existing) if existing.is_dir == entry.is_dir => {},
+                _ if entry.is_dir => actions.push(Action::DeleteDir(entry.path.clone())),
+                _ => actions.push(Action::Delete(entry.path.clone()))
+            }
+        }
+    }
+
+    actions
+}
+
+// Joins a listing relative path onto a remote directory
+pub fn remote_path(dir: &str, path: &str) -> String {
+    let dir = dir.trim_end_matches('/');
+    if dir.is_empty() || dir == "." {
+        String::from(path)
+    }
+    else {
+        format!("{}/{}", dir, path)
+    }
+}
+
+// Last synced version of a file, both sides agreed on it at that point
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncedFile {
+    pub size: u64,
+    pub local_mtime: u64,
+    pub remote_mtime: u64
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    // The remote directory this state was recorded against
+    pub remote: String,
+    pub files: BTreeMap<String, SyncedFile>
+}
+
+impl SyncState {
+    // A missing state, or one recorded against another directory, starts over
+    pub fn load(local: &Path, remote: &str) -> io::Result<SyncState> {
+        match fs::read(local.join(listing::SYNC_STATE)) {
+            Ok(bytes) => {
+                let state: SyncState = serde_json::from_slice(&bytes)?;
+                if state.remote == remote {
+                    return Ok(state);
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e)
+        }
+        Ok(SyncState { remote: String::from(remote), files: BTreeMap::new() })
+    }
+
+    pub fn save(&self, local: &Path) -> io::Result<()> {
+        let path = local.join(listing::SYNC_STATE);
+        let part = local.join(format!("{}.tmp", listing::SYNC_STATE));
+        fs::write(&part, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(part, path)
+    }
+
+    // Records every file that is the same on both sides
+    pub fn record(&mut self, local: &[Entry], remote: &[Entry]) {
+        let remote_map: HashMap<&str, &Entry> = remote.iter().map(|e| (e.path.as_str(), e)).collect();
+        self.files.clear();
+
+        for entry in local.iter().filter(|e| !e.is_dir) {
+            if let Some(other) = remote_map.get(entry.path.as_str()) {
+                if !changed(entry, other, Compare::SizeAndTime) {
+                    self.files.insert(entry.path.clone(), SyncedFile { size: entry.size, local_mtime: entry.mtime, remote_mtime: other.mtime });
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", content = "path", rename_all = "snake_case")]
+pub enum TwoWayAction {
+    Upload(String),
+    Download(String),
+    DeleteLocal(String),
+    DeleteRemote(String),
+    // Edited on both sides since the last sync
+    Conflict(String)
+}
+
+impl fmt::Display for TwoWayAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TwoWayAction::Upload(path) => write!(f, "upload   {}", path),
+            TwoWayAction::Download(path) => write!(f, "download {}", path),
+            TwoWayAction::DeleteLocal(path) => write!(f, "delete   {} (local)", path),
+            TwoWayAction::DeleteRemote(path) => write!(f, "delete   {} (remote)", path),
+            TwoWayAction::Conflict(path) => write!(f, "conflict {}", path)
+        }
+    }
+}
+
+fn side_changed(entry: Option<&Entry>, synced: Option<u64>, size: Option<u64>) -> bool {
+    match (entry, synced, size) {
+        (Some(entry), Some(mtime), Some(size)) => entry.size != size || !same_time(entry.mtime, mtime),
+        (None, None, _) => false,
+        _ => true
+    }
+}
+
+pub fn plan_two_way(local: &[Entry], remote: &[Entry], state: &SyncState) -> Vec<TwoWayAction> {
+    let local_map: HashMap<&str, &Entry> = local.iter().filter(|e| !e.is_dir).map(|e| (e.path.as_str(), e)).collect();
+    let remote_map: HashMap<&str, &Entry> = remote.iter().filter(|e| !e.is_dir).map(|e| (e.path.as_str(), e)).collect();
+
+    let mut paths: Vec<&str> = local_map.keys().chain(remote_map.keys()).copied()
+        .chain(state.files.keys().map(|p| p.as_str()))
+        .collect();
+    paths.sort_unstable();
+    paths.dedup();
+
+    let mut actions = Vec::new();
+    for path in paths {
+        let l = local_map.get(path).copied();
+        let r = remote_map.get(path).copied();
+        let synced = state.files.get(path);
+
+        let local_changed = side_changed(l, synced.map(|s| s.local_mtime), synced.map(|s| s.size));
+        let remote_changed = side_changed(r, synced.map(|s| s.remote_mtime), synced.map(|s| s.size));
+
+        let action = match (local_changed, remote_changed, l, r) {
+            (false, false, _, _) => None,
+            (true, false, Some(_), _) => Some(TwoWayAction::Upload(String::from(path))),
+            (true, false, None, Some(_)) => Some(TwoWayAction::DeleteRemote(String::from(path))),
+            (false, true, _, Some(_)) => Some(TwoWayAction::Download(String::from(path))),
+            (false, true, Some(_), None) => Some(TwoWayAction::DeleteLocal(String::from(path))),
+            // Changed on both sides
+            (_, _, Some(l), Some(r)) if !changed(l, r, Compare::SizeAndTime) => None,
+            (_, _, Some(_), Some(_)) => Some(TwoWayAction::Conflict(String::from(path))),
+            // An edit beats a delete on the other side
+            (_, _, Some(_), None) => Some(TwoWayAction::Upload(String::from(path))),
+            (_, _, None, Some(_)) => Some(TwoWayAction::Download(String::from(path))),
+            _ => None
+        };
+
+        if let Some(action) = action {
+            actions.push(action);
+        }
+    }
+    actions
+}
+
+// Where the local side of a conflict is kept, e.g. notes.txt.conflict-laptop-2026-10-18
+pub fn conflict_path(path: &str, host: &str, date: &str, exists: impl Fn(&str) -> bool) -> String {
+    let base = format!("{}.conflict-{}-{}", path, host, date);
+    let mut candidate = base.clone();
+    let mut n = 2;
+    while exists(&candidate) {
+        candidate = format!("{}-{}", base, n);
+        n += 1;
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1000000000;
+
+    fn file(path: &str, size: u64, mtime: u64) -> Entry {
+        Entry { path: String::from(path), is_dir: false, size, mtime: mtime * SECOND, hash: None }
+    }
+
+    fn dir(path: &str) -> Entry {
+        Entry { path: String::from(path), is_dir: true, size: 0, mtime: 0, hash: None }
+    }
+
+    #[test]
+    fn plan_copies_new_and_changed_files() {
+        let source = [file("a", 1, 10), file("b", 2, 10), file("c", 3, 10), file("d", 4, 10)];
+        let dest = [file("a", 1, 10), file("b", 5, 10), file("c", 3, 11)];
+        assert_eq!(plan(&source, &dest, Compare::SizeAndTime, false), [
+            Action::Update(String::from("b")),
+            Action::Update(String::from("c")),
+            Action::Create(String::from("d"))
+        ]);
+    }
+
+    #[test]
+    fn plan_ignores_sub_second_differences() {
+        let mut dest = file("a", 1, 10);
+        dest.mtime += SECOND / 2;
+        assert!(plan(&[file("a", 1, 10)], &[dest], Compare::SizeAndTime, false).is_empty());
+    }
+
+    #[test]
+    fn plan_compares_checksums() {
+        let mut source = file("a", 1, 10);
+        source.hash = Some([1; listing::HASH_SIZE]);
+        let mut dest = file("a", 1, 99);
+        dest.hash = source.hash;
+        assert!(plan(&[source.clone()], &[dest.clone()], Compare::Checksum, false).is_empty());
+
+        dest.hash = Some([2; listing::HASH_SIZE]);
+        assert_eq!(plan(&[source], &[dest], Compare::Checksum, false), [Action::Update(String::from("a"))]);
+    }
+
+    #[test]
+    fn plan_deletes_children_before_their_directory() {
+        let dest = [dir("old"), file("old/x", 1, 10), file("stale", 1, 10)];
+        assert!(plan(&[], &dest, Compare::SizeAndTime, false).is_empty());
+        assert_eq!(plan(&[], &dest, Compare::SizeAndTime, true), [
+            Action::Delete(String::from("stale")),
+            Action::Delete(String::from("old/x")),
+            Action::DeleteDir(String::from("old"))
+        ]);
+    }
+
+    fn synced(files: &[(&str, u64, u64, u64)]) -> SyncState {
+        let files = files.iter()
+            .map(|(path, size, local, remote)| (String::from(*path), SyncedFile { size: *size, local_mtime: local * SECOND, remote_mtime: remote * SECOND }))
+            .collect();
+        SyncState { remote: String::new(), files }
+    }
+
+    #[test]
+    fn two_way_follows_the_side_that_changed() {
+        let state = synced(&[("same", 1, 10, 20), ("edited", 1, 10, 20), ("pulled", 1, 10, 20), ("removed", 1, 10, 20), ("gone", 1, 10, 20)]);
+        let local = [file("same", 1, 10), file("edited", 2, 30), file("pulled", 1, 10), file("gone", 1, 10), file("fresh", 1, 40)];
+        let remote = [file("same", 1, 20), file("edited", 1, 20), file("pulled", 3, 50), file("removed", 1, 20)];
+        assert_eq!(plan_two_way(&local, &remote, &state), [
+            TwoWayAction::Upload(String::from("edited")),
+            TwoWayAction::Upload(String::from("fresh")),
+            // Deleted on the remote side
+            TwoWayAction::DeleteLocal(String::from("gone")),
+            TwoWayAction::Download(String::from("pulled")),
+            // Deleted on this side
+            TwoWayAction::DeleteRemote(String::from("removed"))
+        ]);
+    }
+
+    #[test]
+    fn two_way_conflicts_when_both_sides_changed() {
+        let state = synced(&[("both", 1, 10, 20), ("agreed", 1, 10, 20), ("kept", 1, 10, 20)]);
+        let local = [file("both", 2, 30), file("agreed", 2, 30), file("kept", 2, 30), file("new", 1, 10)];
+        let remote = [file("both", 3, 40), file("agreed", 2, 30), file("new", 2, 10)];
+        assert_eq!(plan_two_way(&local, &remote, &state), [
+            TwoWayAction::Conflict(String::from("both")),
+            // An edit wins over a delete
+            TwoWayAction::Upload(String::from("kept")),
+            TwoWayAction::Conflict(String::from("new"))
+        ]);
+    }
+
+    #[test]
+    fn conflict_paths_never_overwrite() {
+        assert_eq!(conflict_path("notes.txt", "laptop", "2026-10-18", |_| false), "notes.txt.conflict-laptop-2026-10-18");
+        let taken = ["notes.txt.conflict-laptop-2026-10-18", "notes.txt.conflict-laptop-2026-10-18-2"];
+        assert_eq!(conflict_path("notes.txt", "laptop", "2026-10-18", |p| taken.contains(&p)), "notes.txt.conflict-laptop-2026-10-18-3");
+    }
+}
@@ -0,0 +1,163 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::thread;
+
+// Token bucket, tokens are bytes
+#[derive(Debug)]
+struct TokenBucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+
+        // Allow at most one second worth of burst
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+    }
+
+    // Take the bytes and return how long the caller has to wait to pay them back
+    fn take(&mut self, bytes: usize) -> Duration {
+        match self.rate {
+            Some(rate) => {
+                self.refill(rate);
+                self.tokens -= bytes as f64;
+
+                if self.tokens < 0.0 {
+                    Duration::from_secs_f64(-self.tokens / rate as f64)
+                }
+                else {
+                    Duration::from_secs(0)
+                }
+            },
+            None => Duration::from_secs(0)
+        }
+    }
+}
+
+// Shareable handle, clones throttle against the same bucket
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    bucket: Arc<Mutex<TokenBucket>>
+}
+
+impl Limiter {
+    pub fn new(rate: Option<u64>) -> Limiter {
+        let tokens = rate.unwrap_or(0) as f64;
+        Limiter { bucket: Arc::new(Mutex::new(TokenBucket { rate, tokens, last: Instant::now() })) }
+    }
+
+    pub fn unlimited() -> Limiter {
+        Limiter::new(None)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = rate;
+        bucket.tokens = rate.unwrap_or(0) as f64;
+        bucket.last = Instant::now();
+    }
+
+    // Blocks until the bytes are allowed through
+    pub fn acquire(&self, bytes: usize) {
+        // Don't hold the lock while sleeping so other connections keep their share
+        let wait = self.bucket.lock().unwrap().take(bytes);
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Limiter {
+        Limiter::unlimited()
+    }
+}
+
+// Waits on every limiter in turn, e.g. the global and the per-connection one
+pub fn acquire_all(limiters: &[Limiter], bytes: usize) {
+    for limiter in limiters.iter() {
+        limiter.acquire(bytes);
+    }
+}
+
+// The most to send in one go so that no limiter holds it back for more than a quarter of a second
+pub fn slice(limiters: &[Limiter], max: usize) -> usize {
+    limiters.iter().filter_map(Limiter::rate).map(|rate| rate as usize / 4).fold(max, usize::min).max(1)
+}
+
+pub fn format_rate(rate: Option<u64>) -> String {
+    match rate {
+        Some(rate) => {
+            let (rate, unit) = crate::encoding::get_rate(rate as usize);
+            format!("{} {}/s", rate, unit)
+        },
+        None => String::from("unlimited")
+    }
+}
+
+// Parses rates like "512K", "10M", "1.5GB" or "off" into bytes per second
+pub fn parse_rate(rate: &str) -> Option<Option<u64>> {
+    let rate = rate.trim().to_ascii_uppercase();
+    if rate == "OFF" || rate == "NONE" || rate == "0" {
+        return Some(None);
+    }
+
+    let rate = rate.trim_end_matches("/S").trim_end_matches('B');
+    let (number, multiplier) = match rate.chars().last() {
+        Some('K') => (&rate[..rate.len() - 1], 1000.0),
+        Some('M') => (&rate[..rate.len() - 1], 1000000.0),
+        Some('G') => (&rate[..rate.len() - 1], 1000000000.0),
+        _ => (rate, 1.0)
+    };
+
+    // Anything under one byte a second would leave the bucket with no rate
+    match number.parse::<f64>() {
+        Ok(n) if (n * multiplier) as u64 > 0 => Some(Some((n * multiplier) as u64)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("512"), Some(Some(512)));
+        assert_eq!(parse_rate("512K"), Some(Some(512000)));
+        assert_eq!(parse_rate("10m"), Some(Some(10000000)));
+        assert_eq!(parse_rate(" 1.5GB/s "), Some(Some(1500000000)));
+        assert_eq!(parse_rate("0.5K"), Some(Some(500)));
+    }
+
+    #[test]
+    fn off_lifts_the_limit() {
+        for rate in ["off", "OFF", "none", "0"] {
+            assert_eq!(parse_rate(rate), Some(None));
+        }
+    }
+
+    #[test]
+    fn rejects_rates_below_a_byte_a_second() {
+        for rate in ["0.5", "0.0001K", "-1", "-5M", "0K", "", "K", "fast", "NaN"] {
+            assert_eq!(parse_rate(rate), None, "{:?}", rate);
+        }
+    }
+
+    #[test]
+    fn slice_follows_the_slowest_limiter() {
+        let limiters = [Limiter::new(Some(4000)), Limiter::new(Some(400)), Limiter::unlimited()];
+        assert_eq!(slice(&limiters, 1024), 100);
+        assert_eq!(slice(&[Limiter::unlimited()], 1024), 1024);
+        assert_eq!(slice(&[Limiter::new(Some(1))], 1024), 1);
+    }
+}
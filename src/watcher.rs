@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use crate::listing;
+
+// Editor swap files, download managers and our own partial files
+pub fn is_temporary(name: &str) -> bool {
+    const SUFFIXES: &[&str] = &["~", ".swp", ".swx", ".tmp", ".part", ".crdownload"];
+    listing::is_ignored(name)
+        || name.starts_with(".#")
+        || name.starts_with(".goutputstream")
+        || name == "4913"
+        || SUFFIXES.iter().any(|s| name.ends_with(s))
+}
+
+// Paths created or written to under root, until the watcher is dropped
+pub fn watch(root: &Path) -> io::Result<(RecommendedWatcher, Receiver<PathBuf>)> {
+    let to_io = |e: notify::Error| io::Error::other(e.to_string());
+    let (sender, receiver) = mpsc::channel();
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                for path in event.paths {
+                    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                    if !is_temporary(&name) {
+                        sender.send(path).ok();
+                    }
+                }
+            }
+        }
+    }).map_err(to_io)?;
+    watcher.watch(root, RecursiveMode::Recursive).map_err(to_io)?;
+
+    Ok((watcher, receiver))
+}
+
+// Holds paths back until they have gone quiet, so files are sent once they are fully written
+pub struct Debouncer {
+    settle: Duration,
+    pending: HashMap<PathBuf, Instant>
+}
+
+impl Debouncer {
+    pub fn new(settle: Duration) -> Debouncer {
+        Debouncer { settle, pending: HashMap::new() }
+    }
+
+    pub fn touch(&mut self, path: PathBuf) {
+        self.pending.insert(path, Instant::now());
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    // Until the next path settles
+    pub fn wait(&self) -> Duration {
+        let now = Instant::now();
+        self.pending.values()
+            .map(|touched| (*touched + self.settle).saturating_duration_since(now))
+            .min()
+            .unwrap_or(self.settle)
+    }
+
+    // Paths that have been quiet long enough, they are no longer pending
+    pub fn ready(&mut self) -> Vec<PathBuf> {
+        let settle = self.settle;
+        let mut ready: Vec<PathBuf> = self.pending.iter()
+            .filter(|(_, touched)| touched.elapsed() >= settle)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in ready.iter() {
+            self.pending.remove(path);
+        }
+        ready.sort();
+        ready
+    }
+}
@@ -0,0 +1,252 @@
+// A server in a scratch directory, and a proxy in front of it that misbehaves on request
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use netfolder::net::Target;
+use netfolder::net::client::session::Session;
+use netfolder::output;
+use netfolder::retry::RetryPolicy;
+
+static SCRATCH: AtomicUsize = AtomicUsize::new(0);
+
+// A directory of its own for every test, removed when it's dropped
+pub struct Scratch {
+    pub path: PathBuf
+}
+
+impl Scratch {
+    pub fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!("netfolder-test-{}-{}-{}", std::process::id(), name, SCRATCH.fetch_add(1, Ordering::Relaxed)));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).expect("Unable to create a scratch directory");
+        Scratch { path }
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+// The real binary, since the server serves its working directory
+pub struct Server {
+    pub port: u16,
+    pub root: Scratch,
+    child: Child
+}
+
+impl Server {
+    pub fn start() -> Server {
+        Server::start_with(&[])
+    }
+
+    // With extra server options, like a bandwidth limit
+    pub fn start_with(args: &[&str]) -> Server {
+        let root = Scratch::new("server");
+        let mut child = Command::new(env!("CARGO_BIN_EXE_netfolder"))
+            .args(["server", "-p", "0", "--log-level", "info"])
+            .args(args)
+            .current_dir(&root.path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Unable to start the server");
+
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let port = lines.by_ref().map_while(Result::ok)
+            .find_map(|line| line.split("Listening on port ").nth(1).and_then(|port| port.trim().parse().ok()))
+            .expect("The server never started listening");
+        // Keeps reading so a full pipe never blocks the server
+        thread::spawn(move || lines.for_each(drop));
+
+        Server { port, root, child }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // Client to server
+    Up,
+    Down
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CutKind {
+    // Both ends see the stream end cleanly
+    Truncate,
+    // The client sees a reset
+    Reset
+}
+
+// Ends a connection once this many bytes went one way
+#[derive(Debug, Clone, Copy)]
+pub struct Cut {
+    pub direction: Direction,
+    pub after: u64,
+    pub kind: CutKind
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    // Before every read is passed on
+    pub latency: Duration,
+    // Bytes per second each way
+    pub rate: Option<u64>,
+    // Largest write, so the far side gets packets in pieces
+    pub fragment: Option<usize>,
+    pub cut: Option<Cut>,
+    // How many connections get cut, the ones after that are left alone
+    pub cuts: usize
+}
+
+pub struct Proxy {
+    pub port: u16
+}
+
+impl Proxy {
+    pub fn start(server: &Server, faults: Faults) -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind the proxy");
+        let port = listener.local_addr().unwrap().port();
+        let upstream = server.port;
+        let shared = Arc::new(Mutex::new(faults));
+
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = match client {
+                    Ok(client) => client,
+                    Err(_) => continue
+                };
+                let server = match TcpStream::connect(("127.0.0.1", upstream)) {
+                    Ok(server) => server,
+                    Err(_) => continue
+                };
+                // Each connection keeps the faults it started with
+                let mut faults = shared.lock().unwrap();
+                let connection = faults.clone();
+                if faults.cuts > 0 {
+                    faults.cuts -= 1;
+                }
+                drop(faults);
+                relay(client, server, connection);
+            }
+        });
+
+        Proxy { port }
+    }
+
+    pub fn target(&self) -> Target {
+        Target::parse("127.0.0.1", Some(self.port)).unwrap()
+    }
+}
+
+fn relay(client: TcpStream, server: TcpStream, faults: Faults) {
+    let cut = if faults.cuts > 0 { faults.cut } else { None };
+    let done = Arc::new(AtomicBool::new(false));
+    for direction in [Direction::Up, Direction::Down] {
+        let (from, to) = match direction {
+            Direction::Up => (client.try_clone().unwrap(), server.try_clone().unwrap()),
+            Direction::Down => (server.try_clone().unwrap(), client.try_clone().unwrap())
+        };
+        let ends = (client.try_clone().unwrap(), server.try_clone().unwrap());
+        let cut = cut.filter(|cut| cut.direction == direction);
+        let faults = faults.clone();
+        let done = done.clone();
+        thread::spawn(move || pump(from, to, ends, &faults, cut, &done));
+    }
+}
+
+fn pump(mut from: TcpStream, mut to: TcpStream, ends: (TcpStream, TcpStream), faults: &Faults, cut: Option<Cut>, done: &AtomicBool) {
+    let mut buf = vec![0; 16 * 1024];
+    let mut sent = 0u64;
+    to.set_nodelay(true).ok();
+    loop {
+        let read = match from.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read
+        };
+        if done.load(Ordering::SeqCst) {
+            break;
+        }
+        thread::sleep(faults.latency);
+
+        let allowed = match cut {
+            Some(cut) => (cut.after.saturating_sub(sent) as usize).min(read),
+            None => read
+        };
+        if write(&mut to, &buf[..allowed], faults).is_err() {
+            break;
+        }
+        sent += allowed as u64;
+
+        if let Some(cut) = cut.filter(|_| allowed < read) {
+            done.store(true, Ordering::SeqCst);
+            match cut.kind {
+                CutKind::Truncate => ends.0.shutdown(Shutdown::Both).ok(),
+                // Closed with linger 0 once the other pump lets go, which sends a reset instead of the usual goodbye
+                CutKind::Reset => {
+                    socket2::SockRef::from(&ends.0).set_linger(Some(Duration::from_secs(0))).ok();
+                    ends.0.shutdown(Shutdown::Read).ok()
+                }
+            };
+            ends.1.shutdown(Shutdown::Both).ok();
+            return;
+        }
+    }
+    // One side went away, so does the other, unless a cut already took care of it
+    if !done.swap(true, Ordering::SeqCst) {
+        ends.0.shutdown(Shutdown::Both).ok();
+        ends.1.shutdown(Shutdown::Both).ok();
+    }
+}
+
+fn write(to: &mut TcpStream, data: &[u8], faults: &Faults) -> std::io::Result<()> {
+    let piece = faults.fragment.unwrap_or(data.len()).max(1);
+    for chunk in data.chunks(piece) {
+        to.write_all(chunk)?;
+        if let Some(rate) = faults.rate {
+            thread::sleep(Duration::from_secs_f64(chunk.len() as f64 / rate as f64));
+        }
+        else if faults.fragment.is_some() {
+            // Gives each piece a chance to arrive on its own
+            thread::sleep(Duration::from_micros(50));
+        }
+    }
+    Ok(())
+}
+
+pub fn connect(proxy: &Proxy, retries: u32) -> std::io::Result<Session> {
+    connect_with(proxy, RetryPolicy { retries, timeout: Some(Duration::from_secs(10)) })
+}
+
+pub fn connect_with(proxy: &Proxy, policy: RetryPolicy) -> std::io::Result<Session> {
+    // Keeps transfers from printing progress over the test output
+    output::set_mode(output::Mode::Log);
+    Session::connect(proxy.target(), policy)
+}
+
+// Bytes that don't compress and are unlikely to line up with packet boundaries by accident
+pub fn write_file(path: &Path, len: usize) -> Vec<u8> {
+    let data: Vec<u8> = (0..len).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    fs::create_dir_all(path.parent().unwrap()).expect("Unable to create a test directory");
+    fs::write(path, &data).expect("Unable to write a test file");
+    data
+}
@@ -0,0 +1,210 @@
+// Uploads, downloads, deletes and listings through a proxy that delays, slows, splits and cuts the traffic
+mod common;
+
+use std::fs;
+use std::time::{Duration, Instant};
+use netfolder::net::client::commands;
+use netfolder::net::client::session::Session;
+use netfolder::retry::RetryPolicy;
+use netfolder::{listing, sync};
+use common::{Cut, CutKind, Direction, Faults, Proxy, Scratch, Server};
+
+fn upload(session: &mut Session, local: &std::path::Path, remote: &str) -> std::io::Result<netfolder::stats::TransferStats> {
+    session.run(|transmitter, _, stream| commands::upload(transmitter, stream, local, remote, false))
+}
+
+fn download(session: &mut Session, remote: &str, local: &std::path::Path) -> std::io::Result<netfolder::stats::TransferStats> {
+    let local = local.to_str().unwrap();
+    session.run(|_, receiver, stream| commands::download(receiver, stream, remote, local, false))
+}
+
+fn names(session: &mut Session) -> Vec<(String, u64)> {
+    let entries = session.run(|_, receiver, stream| commands::list(receiver, stream, "", false)).expect("Listing failed");
+    entries.into_iter().filter(|e| !e.is_dir).map(|e| (e.path, e.size)).collect()
+}
+
+// Everything a client does in a session, checked end to end
+fn round_trip(faults: Faults, len: usize) {
+    let server = Server::start();
+    let proxy = Proxy::start(&server, faults);
+    let client = Scratch::new("client");
+    let data = common::write_file(&client.join("sent.bin"), len);
+    let mut session = common::connect(&proxy, 0).expect("Unable to connect");
+
+    upload(&mut session, &client.join("sent.bin"), "dir/file.bin").expect("Upload failed");
+    assert_eq!(fs::read(server.root.join("dir/file.bin")).unwrap(), data);
+    assert_eq!(names(&mut session), vec![(String::from("dir/file.bin"), len as u64)]);
+
+    download(&mut session, "dir/file.bin", &client.join("received.bin")).expect("Download failed");
+    assert_eq!(fs::read(client.join("received.bin")).unwrap(), data);
+
+    session.replaying(|_, receiver, stream, replay| commands::delete(receiver, stream, "dir/file.bin", replay)).expect("Delete failed");
+    assert!(!server.root.join("dir/file.bin").exists());
+    assert!(names(&mut session).is_empty());
+    assert!(session.replaying(|_, receiver, stream, replay| commands::delete(receiver, stream, "dir/file.bin", replay)).is_err());
+}
+
+#[test]
+fn clean_proxy() {
+    round_trip(Faults::default(), 300_000);
+}
+
+#[test]
+fn fragmented_packets() {
+    round_trip(Faults { fragment: Some(7), ..Faults::default() }, 40_000);
+}
+
+// Every packet header arrives a byte at a time
+#[test]
+fn single_byte_writes() {
+    round_trip(Faults { fragment: Some(1), ..Faults::default() }, 100);
+}
+
+#[test]
+fn empty_file_in_pieces() {
+    round_trip(Faults { fragment: Some(3), ..Faults::default() }, 0);
+}
+
+#[test]
+fn latency() {
+    round_trip(Faults { latency: Duration::from_millis(20), ..Faults::default() }, 200_000);
+}
+
+#[test]
+fn throttled_link() {
+    let server = Server::start();
+    let proxy = Proxy::start(&server, Faults { rate: Some(200_000), ..Faults::default() });
+    let client = Scratch::new("client");
+    let data = common::write_file(&client.join("sent.bin"), 400_000);
+    let mut session = common::connect(&proxy, 0).unwrap();
+
+    let instant = Instant::now();
+    upload(&mut session, &client.join("sent.bin"), "sent.bin").expect("Upload failed");
+    assert!(instant.elapsed() >= Duration::from_millis(1500), "400 KB at 200 KB/s took {:?}", instant.elapsed());
+    assert_eq!(fs::read(server.root.join("sent.bin")).unwrap(), data);
+}
+
+#[test]
+fn download_resumes_after_truncation() {
+    let server = Server::start();
+    let data = common::write_file(&server.root.join("big.bin"), 2_000_000);
+    // Past the first chunk, only whole chunks are kept to resume from
+    let cut = Cut { direction: Direction::Down, after: 1_500_000, kind: CutKind::Truncate };
+    let proxy = Proxy::start(&server, Faults { cut: Some(cut), cuts: 1, ..Faults::default() });
+    let client = Scratch::new("client");
+    let mut session = common::connect(&proxy, 3).unwrap();
+
+    let stats = download(&mut session, "big.bin", &client.join("big.bin")).expect("Download failed");
+    assert_eq!(fs::read(client.join("big.bin")).unwrap(), data);
+    // Only the rest came over the second connection
+    assert!(stats.bytes() < data.len(), "Sent all {} bytes again", stats.bytes());
+}
+
+#[test]
+fn upload_resumes_after_reset() {
+    let server = Server::start();
+    let cut = Cut { direction: Direction::Up, after: 1_500_000, kind: CutKind::Reset };
+    let proxy = Proxy::start(&server, Faults { cut: Some(cut), cuts: 1, ..Faults::default() });
+    let client = Scratch::new("client");
+    let data = common::write_file(&client.join("big.bin"), 2_000_000);
+    let mut session = common::connect(&proxy, 3).unwrap();
+
+    let stats = upload(&mut session, &client.join("big.bin"), "big.bin").expect("Upload failed");
+    assert_eq!(fs::read(server.root.join("big.bin")).unwrap(), data);
+    assert!(stats.bytes() < data.len(), "Sent all {} bytes again", stats.bytes());
+}
+
+#[test]
+fn cut_without_retries_fails_cleanly() {
+    let server = Server::start();
+    common::write_file(&server.root.join("big.bin"), 1_000_000);
+    let cut = Cut { direction: Direction::Down, after: 300_000, kind: CutKind::Reset };
+    let proxy = Proxy::start(&server, Faults { cut: Some(cut), cuts: 1, ..Faults::default() });
+    let client = Scratch::new("client");
+    let mut session = common::connect(&proxy, 0).unwrap();
+
+    assert!(download(&mut session, "big.bin", &client.join("big.bin")).is_err());
+    // Never a half written file under the real name
+    assert!(!client.join("big.bin").exists());
+
+    // The server is still there for the next client
+    let mut session = common::connect(&proxy, 0).unwrap();
+    assert_eq!(names(&mut session), vec![(String::from("big.bin"), 1_000_000)]);
+}
+
+#[test]
+fn listing_through_slow_fragments() {
+    let server = Server::start();
+    for i in 0..20 {
+        common::write_file(&server.root.join(&format!("dir/file-{:02}.txt", i)), i * 100);
+    }
+    let proxy = Proxy::start(&server, Faults { latency: Duration::from_millis(5), fragment: Some(5), ..Faults::default() });
+    let mut session = common::connect(&proxy, 0).unwrap();
+
+    let mut listed = names(&mut session);
+    listed.sort();
+    let expected: Vec<_> = (0..20).map(|i| (format!("dir/file-{:02}.txt", i), i as u64 * 100)).collect();
+    assert_eq!(listed, expected);
+}
+
+// A whole 1 MiB chunk at 200 KB/s is twice the timeout, like one at 16 KB/s against the default 30 s
+#[test]
+fn server_limit_within_timeout() {
+    let server = Server::start_with(&["--conn-limit", "200K"]);
+    let data = common::write_file(&server.root.join("big.bin"), 1_200_000);
+    let proxy = Proxy::start(&server, Faults::default());
+    let client = Scratch::new("client");
+    let mut session = common::connect_with(&proxy, RetryPolicy { retries: 0, timeout: Some(Duration::from_secs(2)) }).unwrap();
+
+    download(&mut session, "big.bin", &client.join("big.bin")).expect("Download failed");
+    assert_eq!(fs::read(client.join("big.bin")).unwrap(), data);
+}
+
+// The listing is asked for again in full, never pieced together from two connections
+#[test]
+fn listing_cut_midway() {
+    let server = Server::start();
+    for i in 0..50 {
+        common::write_file(&server.root.join(&format!("file-{:02}.txt", i)), i * 10);
+    }
+    let cut = Cut { direction: Direction::Down, after: 1500, kind: CutKind::Reset };
+    let proxy = Proxy::start(&server, Faults { cut: Some(cut), cuts: 1, ..Faults::default() });
+    let mut session = common::connect(&proxy, 3).unwrap();
+
+    let mut listed = names(&mut session);
+    listed.sort();
+    let expected: Vec<_> = (0..50).map(|i| (format!("file-{:02}.txt", i), i as u64 * 10)).collect();
+    assert_eq!(listed, expected);
+}
+
+// The server deleted the file but the reply never made it, so the delete sent again finds nothing there
+#[test]
+fn delete_after_reconnect() {
+    let server = Server::start();
+    common::write_file(&server.root.join("gone.bin"), 1000);
+    let cut = Cut { direction: Direction::Down, after: 0, kind: CutKind::Reset };
+    let proxy = Proxy::start(&server, Faults { cut: Some(cut), cuts: 1, ..Faults::default() });
+    let mut session = common::connect(&proxy, 3).unwrap();
+
+    session.replaying(|_, receiver, stream, replay| commands::delete(receiver, stream, "gone.bin", replay)).expect("Delete failed");
+    assert!(!server.root.join("gone.bin").exists());
+}
+
+// Nothing but copies go over the wire for a file that didn't change, its mtime has to come with them
+#[test]
+fn delta_update_keeps_mtime() {
+    let server = Server::start();
+    let proxy = Proxy::start(&server, Faults::default());
+    let client = Scratch::new("client");
+    common::write_file(&client.join("same.bin"), 200_000);
+    let old = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    fs::File::options().write(true).open(client.join("same.bin")).unwrap().set_modified(old).unwrap();
+    let mut session = common::connect(&proxy, 0).unwrap();
+
+    upload(&mut session, &client.join("same.bin"), "same.bin").expect("Upload failed");
+    session.run(|transmitter, _, stream| commands::upload(transmitter, stream, &client.join("same.bin"), "same.bin", true)).expect("Delta upload failed");
+
+    let local = listing::scan(&client.path, false).unwrap();
+    let remote = session.run(|_, receiver, stream| commands::list(receiver, stream, "", false)).unwrap();
+    assert!(sync::plan(&local, &remote, sync::Compare::SizeAndTime, false).is_empty());
+}